@@ -1,9 +1,14 @@
 use quote::quote;
+use syn::ext::IdentExt;
 
-pub fn handler_derive(s: synstructure::Structure) -> proc_macro::TokenStream {
+pub fn handler_derive(mut s: synstructure::Structure) -> proc_macro::TokenStream {
     let mut commands: Vec<syn::Ident> = Vec::new();
     let mut command_names: Vec<String> = Vec::new();
     let mut password_func: Option<syn::Ident> = None;
+    let mut categories_func: Option<syn::Ident> = None;
+    let mut keys_func: Option<syn::Ident> = None;
+    let mut info_func: Option<syn::Ident> = None;
+    let mut authenticator_field: Option<syn::Ident> = None;
 
     for attr in s.ast().attrs.iter() {
         let meta = attr.parse_meta().unwrap();
@@ -13,7 +18,9 @@ pub fn handler_derive(s: synstructure::Structure) -> proc_macro::TokenStream {
                     match m {
                         syn::NestedMeta::Meta(syn::Meta::Path(p)) => {
                             commands.push(p.segments.first().unwrap().ident.clone());
-                            command_names.push(p.segments.first().unwrap().ident.to_string());
+                            // Raw identifiers like `r#move` implement the command without the prefix
+                            command_names
+                                .push(p.segments.first().unwrap().ident.unraw().to_string());
                         }
                         syn::NestedMeta::Meta(syn::Meta::NameValue(n)) => {
                             commands.push(n.path.segments.first().unwrap().ident.clone());
@@ -21,22 +28,72 @@ pub fn handler_derive(s: synstructure::Structure) -> proc_macro::TokenStream {
                         _ => (),
                     }
                 }
-            } else if list.path.segments.first().unwrap().ident == "password" {
-                if let syn::NestedMeta::Meta(syn::Meta::Path(p)) = &list.nested[0] {
-                    password_func = Some(p.segments.first().unwrap().ident.clone())
+            } else {
+                let func = match &list.nested[0] {
+                    syn::NestedMeta::Meta(syn::Meta::Path(p)) => {
+                        Some(p.segments.first().unwrap().ident.clone())
+                    }
+                    _ => None,
                 };
+
+                let ident = &list.path.segments.first().unwrap().ident;
+                if ident == "password" {
+                    password_func = func;
+                } else if ident == "categories" {
+                    categories_func = func;
+                } else if ident == "keys" {
+                    keys_func = func;
+                } else if ident == "info" {
+                    info_func = func;
+                } else if ident == "authenticator" {
+                    authenticator_field = func;
+                }
             }
         }
     }
 
-    let required = password_func.is_some();
+    let required = password_func.is_some() || authenticator_field.is_some();
 
     let ret = if let Some(p) = &password_func {
         quote! { self.#p(username, password) }
     } else {
-        quote! { true }
+        quote! { !#required }
     };
 
+    let authenticator = authenticator_field.map(|f| {
+        quote! {
+            fn authenticator(&self) -> Option<std::sync::Arc<dyn worm::Authenticator>> {
+                Some(self.#f.clone() as std::sync::Arc<dyn worm::Authenticator>)
+            }
+        }
+    });
+
+    let categories = categories_func.map(|f| {
+        quote! {
+            fn categories(&self, command: &str) -> &[&str] {
+                self.#f(command)
+            }
+        }
+    });
+
+    let keys = keys_func.map(|f| {
+        quote! {
+            fn keys<'a>(&self, command: &'a worm::Command) -> Vec<&'a worm::Value> {
+                self.#f(command)
+            }
+        }
+    });
+
+    let info = info_func.map(|f| {
+        quote! {
+            fn info(&self, info: &mut worm::Info) {
+                self.#f(info)
+            }
+        }
+    });
+
+    s.underscore_const(true);
+
     let command_names = command_names.iter().map(|x| x.as_str()).collect::<Vec<_>>();
     s.gen_impl(quote! {
         gen impl worm::Handler for @Self {
@@ -49,6 +106,11 @@ pub fn handler_derive(s: synstructure::Structure) -> proc_macro::TokenStream {
             fn _check_password(&self, username: &str, password: &str) -> bool {
                 #ret
             }
+
+            #categories
+            #keys
+            #info
+            #authenticator
         }
     })
     .into()
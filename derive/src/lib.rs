@@ -1,3 +1,3 @@
 mod derive;
 
-synstructure::decl_derive!([Handler, attributes(commands, password)] => derive::handler_derive);
+synstructure::decl_derive!([Handler, attributes(commands, password, categories, keys, info, authenticator)] => derive::handler_derive);
@@ -1,45 +1,150 @@
 use worm::*;
 
-
 #[derive(Default, worm::Handler)]
-#[commands(get, set, del, list)]
+#[commands(get, set, del, list, r#move, swapdb)]
 #[password(authorize)]
+#[categories(categories)]
+#[keys(keys)]
+#[info(info)]
 pub struct KV {
-    store: Map,
+    // One map for each database that has been written to, indexed by `Client::db`
+    dbs: std::collections::BTreeMap<usize, Map>,
 }
 
 impl KV {
-    async fn set(&mut self, _client: std::pin::Pin<&mut Client>, mut command: Command) -> Response {
+    fn store(&mut self, db: usize) -> &mut Map {
+        self.dbs.entry(db).or_default()
+    }
+
+    async fn set(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
         let key = command.pop_front();
         let value = command.pop_front();
-        self.store.insert(key, value);
-        Ok(Value::ok())
+        if let Some(ctx) = client.context() {
+            ctx.touch(client.db(), &key);
+        }
+        self.store(client.db()).insert(key, value);
+        Ok(Response::ok())
     }
 
-    async fn get(&mut self, client: std::pin::Pin<&mut Client>, mut command: Command) -> Response {
+    async fn get(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
         let key = command.pop_front();
-        if let Some(value) = self.store.get(&key) {
-            client.get_mut().write(value).await?;
-            return Value::done()
+        if let Some(value) = self.store(client.db()).get(&key) {
+            return Ok(value.clone().into());
         }
 
-        Ok(Value::Null)
+        Ok(Value::Null.into())
     }
 
-    async fn del(&mut self, _client: std::pin::Pin<&mut Client>, command: Command) -> Response {
+    async fn del(&mut self, client: std::pin::Pin<&mut Client>, command: Command) -> HandlerResult {
         let args = command.args();
-        self.store.remove(&args[0]);
-        Ok(Value::ok())
+        if let Some(ctx) = client.context() {
+            ctx.touch(client.db(), &args[0]);
+        }
+        self.store(client.db()).remove(&args[0]);
+        Ok(Response::ok())
+    }
+
+    async fn r#move(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        command: Command,
+    ) -> HandlerResult {
+        let (key, db) = match command.args() {
+            [key, db] => (key, db),
+            args => return Error::invalid_args("move", args.len(), 2),
+        };
+        let ctx = client.context().cloned().unwrap_or_default();
+        let db = match ctx.db_index(db) {
+            Ok(db) => db,
+            Err(e) => return Ok(e.into()),
+        };
+
+        if db == client.db() {
+            return Ok(ReplyError::err("source and destination objects are the same").into());
+        }
+
+        if self.store(db).contains_key(key) {
+            return Ok(Value::from(0).into());
+        }
+
+        match self.store(client.db()).remove(key) {
+            Some(value) => {
+                ctx.touch(client.db(), key);
+                ctx.touch(db, key);
+                self.store(db).insert(key.clone(), value);
+                Ok(Value::from(1).into())
+            }
+            None => Ok(Value::from(0).into()),
+        }
+    }
+
+    async fn swapdb(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        command: Command,
+    ) -> HandlerResult {
+        let ctx = client.context().cloned().unwrap_or_default();
+        let (a, b) = match command.args() {
+            [a, b] => (ctx.db_index(a), ctx.db_index(b)),
+            args => return Error::invalid_args("swapdb", args.len(), 2),
+        };
+        let (a, b) = match (a, b) {
+            (Ok(a), Ok(b)) => (a, b),
+            (Err(e), _) | (_, Err(e)) => return Ok(e.into()),
+        };
+
+        // A key in either database changes in both of them
+        let x = self.dbs.remove(&a).unwrap_or_default();
+        let y = self.dbs.remove(&b).unwrap_or_default();
+        for key in x.keys().chain(y.keys()) {
+            ctx.touch(a, key);
+            ctx.touch(b, key);
+        }
+        self.dbs.insert(a, y);
+        self.dbs.insert(b, x);
+        Ok(Response::ok())
+    }
+
+    async fn list(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        _command: Command,
+    ) -> HandlerResult {
+        let keys = self.store(client.db()).keys().cloned().collect::<Vec<_>>();
+        Ok(Response::stream(futures::stream::iter(keys)))
+    }
+
+    fn categories(&self, command: &str) -> &[&str] {
+        match command {
+            "get" => &["read", "fast"],
+            "set" | "del" => &["write", "fast"],
+            "list" => &["keyspace", "read", "slow"],
+            "move" => &["keyspace", "write", "fast"],
+            "swapdb" => &["keyspace", "write", "fast", "dangerous"],
+            _ => &[],
+        }
+    }
+
+    fn keys<'a>(&self, command: &'a Command) -> Vec<&'a Value> {
+        match command.name() {
+            "get" | "set" | "del" | "move" => command.args().iter().take(1).collect(),
+            _ => vec![],
+        }
     }
 
-    async fn list(&mut self, client: std::pin::Pin<&mut Client>, _command: Command) -> Response {
-        let client = client.get_mut();
-        let this = self;
-        client.output.write_array_header(this.store.len()).await?;
-        for k in this.store.keys() {
-            client.write(k).await?;
+    fn info(&self, info: &mut Info) {
+        info.section("Keyspace");
+        for (db, store) in self.dbs.iter().filter(|(_, x)| !x.is_empty()) {
+            info.field(format!("db{}", db), format!("keys={}", store.len()));
         }
-        Value::done()
     }
 
     fn authorize(&self, user: &str, pass: &str) -> bool {
@@ -50,5 +155,12 @@ impl KV {
 #[tokio::main]
 pub async fn main() -> Result<(), Error> {
     env_logger::init();
-    Server::new(KV::default()).run("127.0.0.1:8080").await
+    Server::builder(KV::default())
+        .bind("127.0.0.1:8080")
+        .shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .build()?
+        .serve()
+        .await
 }
@@ -0,0 +1,371 @@
+use crate::internal::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub(crate) const COMMANDS: &[&str] = &["acl"];
+
+// Categories used by the built-in commands, handlers can add their own using
+// `Handler::categories`
+pub(crate) const CATEGORIES: &[&str] = &[
+    "all",
+    "admin",
+    "connection",
+    "dangerous",
+    "fast",
+    "keyspace",
+    "pubsub",
+    "read",
+    "slow",
+    "transaction",
+    "write",
+];
+
+// Categories of the built-in commands, `None` for commands implemented by the handler
+pub(crate) fn builtin_categories(command: &Command) -> Option<&'static [&'static str]> {
+    let c: &[&str] = match command.name() {
+        "hello" | "auth" | "ping" | "commands" | "reset" | "select" => &["connection", "fast"],
+        "client" => match command.args().first().and_then(|x| x.as_string()) {
+            Some(x)
+                if ["list", "kill", "pause", "unpause"]
+                    .iter()
+                    .any(|y| x.eq_ignore_ascii_case(y)) =>
+            {
+                &["admin", "connection", "dangerous", "slow"]
+            }
+            _ => &["connection", "slow"],
+        },
+        "info" => &["slow", "dangerous"],
+        "monitor" => &["admin", "slow", "dangerous"],
+        "slowlog" => &["admin", "slow", "dangerous"],
+        "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "publish" => {
+            &["pubsub", "fast"]
+        }
+        "pubsub" => &["pubsub", "slow"],
+        "multi" | "exec" | "discard" => &["transaction", "fast"],
+        "watch" | "unwatch" => &["transaction", "fast"],
+        "ratelimit" => match command.args().first().and_then(|x| x.as_string()) {
+            Some(x) if x.eq_ignore_ascii_case("reset") => &["admin", "dangerous", "slow"],
+            _ => &["admin", "slow"],
+        },
+        "acl" => match command.args().first().and_then(|x| x.as_string()) {
+            Some(x) if x.eq_ignore_ascii_case("whoami") || x.eq_ignore_ascii_case("cat") => {
+                &["slow"]
+            }
+            _ => &["admin", "dangerous", "slow"],
+        },
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// SHA-256 hash of a password, as a hex string
+pub fn hash_password(password: &str) -> String {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+fn value_str(x: &Value) -> std::borrow::Cow<'_, str> {
+    match x {
+        Value::Bytes(b) => String::from_utf8_lossy(b),
+        Value::String(s) => s.as_str().into(),
+        x => format!("{}", x.as_int().unwrap_or_default()).into(),
+    }
+}
+
+/// ACL user, permissions are modified using the same rules as `ACL SETUSER`
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    passwords: BTreeSet<String>,
+    commands: Vec<String>,
+    keys: Vec<String>,
+    channels: Vec<String>,
+}
+
+impl User {
+    /// Create a disabled user with no passwords or permissions
+    pub fn new(name: impl Into<String>) -> User {
+        User {
+            name: name.into(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: Vec::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Apply a single rule, for example `on`, `>password`, `~cache:*`, `&news.*`, `+@read` or `-del`
+    pub fn apply(&mut self, rule: &str) -> Result<(), ReplyError> {
+        let lower = rule.to_ascii_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".into()],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec!["*".into()],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.commands = vec!["+@all".into()],
+            "nocommands" => self.commands = vec!["-@all".into()],
+            "reset" => *self = User::new(std::mem::take(&mut self.name)),
+            _ => match (rule.chars().next(), rule.get(1..).unwrap_or_default()) {
+                (Some('>'), pass) => {
+                    self.nopass = false;
+                    self.passwords.insert(hash_password(pass));
+                }
+                (Some('<'), pass) => {
+                    self.passwords.remove(&hash_password(pass));
+                }
+                (Some('#'), hash) if is_hash(hash) => {
+                    self.nopass = false;
+                    self.passwords.insert(hash.to_ascii_lowercase());
+                }
+                (Some('!'), hash) if is_hash(hash) => {
+                    self.passwords.remove(&hash.to_ascii_lowercase());
+                }
+                (Some('~'), pattern) => self.keys.push(pattern.into()),
+                (Some('&'), pattern) => self.channels.push(pattern.into()),
+                (Some('+'), x) | (Some('-'), x) if !x.is_empty() => {
+                    // Adding or removing all commands makes every previous rule redundant
+                    if lower[1..] == *"@all" {
+                        self.commands.clear();
+                    }
+                    self.commands.push(lower);
+                }
+                _ => {
+                    return Err(ReplyError::err(format!(
+                        "Error in ACL SETUSER modifier '{}': Syntax error",
+                        rule
+                    )))
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    /// Check if the user can run a command in the given categories, rules are applied in order so
+    /// the last matching rule wins
+    pub fn can_execute(&self, command: &str, categories: &[&str]) -> bool {
+        let mut allowed = false;
+        for rule in &self.commands {
+            let (sign, target) = rule.split_at(1);
+            let matches = match target.strip_prefix('@') {
+                Some(cat) => cat == "all" || categories.contains(&cat),
+                None => target == command,
+            };
+            if matches {
+                allowed = sign == "+";
+            }
+        }
+        allowed
+    }
+
+    pub fn can_access_key(&self, key: &str) -> bool {
+        self.keys.iter().any(|p| glob_match(p, key))
+    }
+
+    pub fn can_access_channel(&self, channel: &str) -> bool {
+        self.channels.iter().any(|p| glob_match(p, channel))
+    }
+
+    /// Description of the user in `ACL LIST` format
+    pub fn describe(&self) -> String {
+        let mut s = format!(
+            "user {} {}",
+            self.name,
+            if self.enabled { "on" } else { "off" }
+        );
+        if self.nopass {
+            s.push_str(" nopass");
+        }
+        for hash in &self.passwords {
+            s.push_str(" #");
+            s.push_str(hash);
+        }
+        for key in &self.keys {
+            s.push_str(" ~");
+            s.push_str(key);
+        }
+        s.push_str(" resetchannels");
+        for channel in &self.channels {
+            s.push_str(" &");
+            s.push_str(channel);
+        }
+        if self.commands.is_empty() {
+            s.push_str(" -@all");
+        }
+        for rule in &self.commands {
+            s.push(' ');
+            s.push_str(rule);
+        }
+        s
+    }
+}
+
+impl From<User> for Value {
+    fn from(user: User) -> Value {
+        let mut flags = vec![Value::from(if user.enabled { "on" } else { "off" })];
+        if user.nopass {
+            flags.push("nopass".into());
+        }
+
+        let strings = |x: Vec<String>| Value::Array(x.into_iter().map(Value::from).collect());
+        map! {
+            "flags" => Value::Array(flags),
+            "passwords" => strings(user.passwords.into_iter().collect()),
+            "commands" => user.commands.join(" "),
+            "keys" => strings(user.keys),
+            "channels" => strings(user.channels),
+        }
+    }
+}
+
+/// Server-wide set of users, available using `ServerContext::acl`
+///
+/// The `default` user is used by connections that haven't authenticated and by users that are
+/// authenticated by the handler's password function or authenticator but don't exist in the ACL
+/// when they authenticate. It starts out without a password and with access to every command, key
+/// and channel. Connections of a user that is removed lose every permission
+pub struct Acl {
+    users: std::sync::Mutex<BTreeMap<String, User>>,
+}
+
+impl Default for Acl {
+    fn default() -> Acl {
+        let mut default = User::new("default");
+        for rule in &["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            let _ = default.apply(rule);
+        }
+
+        let mut users = BTreeMap::new();
+        users.insert(default.name.clone(), default);
+        Acl {
+            users: std::sync::Mutex::new(users),
+        }
+    }
+}
+
+impl Acl {
+    /// Create or modify a user, none of the rules are applied if any of them are invalid
+    pub fn set_user<S: AsRef<str>>(
+        &self,
+        name: &str,
+        rules: impl IntoIterator<Item = S>,
+    ) -> Result<(), ReplyError> {
+        // Whether a rule is valid doesn't depend on the user, so the rules are checked before
+        // taking the lock and applying them can't fail
+        let rules = rules.into_iter().collect::<Vec<_>>();
+        let mut scratch = User::new(name);
+        for rule in &rules {
+            scratch.apply(rule.as_ref())?;
+        }
+
+        let mut users = self.users.lock().unwrap();
+        let user = users.entry(name.into()).or_insert_with(|| User::new(name));
+        for rule in &rules {
+            user.apply(rule.as_ref())?;
+        }
+        Ok(())
+    }
+
+    pub fn user(&self, name: &str) -> Option<User> {
+        self.users.lock().unwrap().get(name).cloned()
+    }
+
+    /// Remove a user, the `default` user can't be removed
+    pub fn del_user(&self, name: &str) -> bool {
+        name != "default" && self.users.lock().unwrap().remove(name).is_some()
+    }
+
+    pub fn users(&self) -> Vec<User> {
+        self.users.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Check a username and password, users without a password only accept any password when
+    /// `allow_nopass` is true
+    pub(crate) fn authenticate(&self, name: &str, password: &str, allow_nopass: bool) -> bool {
+        let users = self.users.lock().unwrap();
+        match users.get(name) {
+            Some(user) if user.nopass => allow_nopass && user.enabled,
+            Some(user) => user.check_password(password),
+            None => false,
+        }
+    }
+
+    /// Check the permissions of `user` to execute a command
+    pub(crate) fn check(
+        &self,
+        user: &str,
+        command: &str,
+        categories: &[&str],
+        keys: &[&Value],
+        channels: &[&Value],
+    ) -> Result<(), ReplyError> {
+        let users = self.users.lock().unwrap();
+        let user = match users.get(user) {
+            Some(u) => u,
+            None => return Err(ReplyError::new(ErrorCode::NoPerm, "unknown user")),
+        };
+
+        if !user.can_execute(command, categories) {
+            return Err(ReplyError::new(
+                ErrorCode::NoPerm,
+                format!(
+                    "this user has no permissions to run the '{}' command",
+                    command
+                ),
+            ));
+        }
+
+        if !keys.iter().all(|k| user.can_access_key(&value_str(k))) {
+            return Err(ReplyError::new(
+                ErrorCode::NoPerm,
+                "this user has no permissions to access one of the keys used as arguments",
+            ));
+        }
+
+        if !channels
+            .iter()
+            .all(|c| user.can_access_channel(&value_str(c)))
+        {
+            return Err(ReplyError::new(
+                ErrorCode::NoPerm,
+                "this user has no permissions to access one of the channels used as arguments",
+            ));
+        }
+
+        Ok(())
+    }
+}
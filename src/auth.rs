@@ -0,0 +1,93 @@
+use crate::internal::*;
+
+/// Identity of an authenticated connection, available to handlers using `Client::principal`
+#[derive(Clone)]
+pub struct Principal {
+    user: String,
+    // ACL user used to check permissions when it's different from `user`
+    acl_user: Option<String>,
+    data: Option<std::sync::Arc<dyn std::any::Any + Send + Sync>>,
+}
+
+impl std::fmt::Debug for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Principal")
+            .field("user", &self.user)
+            .finish()
+    }
+}
+
+impl Principal {
+    pub fn new(user: impl Into<String>) -> Principal {
+        Principal {
+            user: user.into(),
+            acl_user: None,
+            data: None,
+        }
+    }
+
+    /// Attach application data to the principal, it can be read back using `Principal::data`
+    pub fn with_data<T: std::any::Any + Send + Sync>(mut self, data: T) -> Principal {
+        self.data = Some(std::sync::Arc::new(data));
+        self
+    }
+
+    /// Name of the user, this is also the ACL user used to check permissions unless the user
+    /// didn't exist in the ACL when the connection authenticated
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub(crate) fn with_acl_user(mut self, user: impl Into<String>) -> Principal {
+        self.acl_user = Some(user.into());
+        self
+    }
+
+    pub(crate) fn acl_user(&self) -> &str {
+        self.acl_user.as_deref().unwrap_or(&self.user)
+    }
+
+    pub fn data<T: std::any::Any>(&self) -> Option<&T> {
+        self.data.as_ref().and_then(|x| x.downcast_ref())
+    }
+}
+
+/// Asynchronous password verification
+///
+/// Unlike the `#[password(..)]` function, the authenticator runs without holding the handler
+/// lock, so it can call out to an external credential store or use slow password hashing. An
+/// error is sent to the client before it's disconnected
+#[async_trait::async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Principal, ReplyError>;
+}
+
+// Username and password sent by `AUTH` or `HELLO`
+pub(crate) fn credentials(command: &Command) -> Option<(&str, &str)> {
+    let args = command.args();
+    match command.name() {
+        "auth" => match args {
+            [password] => Some(("default", password.as_string()?)),
+            [username, password, ..] => Some((username.as_string()?, password.as_string()?)),
+            [] => None,
+        },
+        "hello" => {
+            // Options are walked the same way as `Handler::handle_hello`
+            let mut i = 1;
+            while i < args.len() {
+                let opt = args[i].as_string()?;
+                if opt.eq_ignore_ascii_case("auth") {
+                    return match &args[i + 1..] {
+                        [username, password, ..] => {
+                            Some((username.as_string()?, password.as_string()?))
+                        }
+                        _ => None,
+                    };
+                }
+                i += 2;
+            }
+            None
+        }
+        _ => None,
+    }
+}
@@ -1,71 +1,300 @@
 use crate::internal::*;
 
+use std::convert::TryFrom;
+
+type Input = Decoder<stream::Counted<tokio::io::ReadHalf<Stream>>>;
+
+enum Reader {
+    Decoder(Input),
+
+    // Once the client has subscribed to a channel the decoder is moved to a background task,
+    // which forwards replies here
+    Routed(tokio::sync::mpsc::UnboundedReceiver<Result<Value, Error>>),
+}
+
+static CLIENT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
 pub struct Client {
     addrs: Vec<std::net::SocketAddr>,
     auth: Option<(String, String)>,
-    pub output: Encoder<tokio::io::WriteHalf<tokio::net::TcpStream>>,
-    pub input: Decoder<tokio::io::ReadHalf<tokio::net::TcpStream>>,
+    /// Write half of the connection, which can be a TCP or Unix socket
+    pub output: Encoder<stream::Counted<tokio::io::WriteHalf<Stream>>>,
+    input: Reader,
     pub(crate) authenticated: bool,
+    pub(crate) id: u64,
+    pub(crate) name: Option<String>,
+    pub(crate) db: usize,
+    pub(crate) principal: Option<Principal>,
+    // Result of the `Authenticator` for the `AUTH` or `HELLO` command being executed
+    pub(crate) verified: Option<Result<Principal, ReplyError>>,
+    pub(crate) context: Option<ServerContext>,
+    pub(crate) push: Option<pubsub::Sender>,
+    pub(crate) subscriptions: std::collections::BTreeSet<String>,
+    pub(crate) patterns: std::collections::BTreeSet<String>,
+    pub(crate) multi: Option<transaction::MultiState>,
+    // Database and key of each watched key
+    pub(crate) watched: Vec<(usize, Value)>,
+    pub(crate) dirty: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    pub(crate) traffic: std::sync::Arc<stream::Traffic>,
+    // Set by `MONITOR`, the connection task takes the receiver and writes each line it gets
+    pub(crate) monitor: Option<monitor::Receiver>,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Recorder,
+    server_info: Option<ServerInfo>,
+    routes: pubsub::Routes,
+    // Pushes other than pub/sub messages, the receiver is taken by `Client::pushes`
+    pushes: tokio::sync::mpsc::Sender<Value>,
+    push_rx: Option<tokio::sync::mpsc::Receiver<Value>>,
 }
 
 impl Client {
     pub(crate) async fn new_from_stream(
-        stream: tokio::net::TcpStream,
+        stream: Stream,
         addrs: Vec<std::net::SocketAddr>,
         auth: Option<(&str, &str)>,
     ) -> Result<Client, Error> {
+        Ok(Client::from_stream(stream, addrs, auth))
+    }
+
+    // Connection state for commands executed in-process, replies are never written to the stream
+    #[cfg(feature = "tower")]
+    pub(crate) fn local(context: ServerContext) -> Client {
+        let (stream, _) = tokio::io::duplex(64);
+        let addr = ([0, 0, 0, 0], 0).into();
+        let mut client = Client::from_stream(stream.into(), vec![addr], None);
+        client.context = Some(context);
+        client
+    }
+
+    fn from_stream(
+        stream: Stream,
+        addrs: Vec<std::net::SocketAddr>,
+        auth: Option<(&str, &str)>,
+    ) -> Client {
+        let traffic = std::sync::Arc::new(stream::Traffic::default());
         let (r, w) = tokio::io::split(stream);
-        let output = Encoder::new(w);
-        let input = Decoder::new(r);
+        let output = Encoder::new(stream::Counted::new(w, traffic.clone()));
+        let input = Reader::Decoder(Decoder::new(stream::Counted::new(r, traffic.clone())));
+        let (pushes, push_rx) = tokio::sync::mpsc::channel(pubsub::PUSH_BUFFER);
 
-        let client = Client {
+        Client {
             addrs,
             output,
             input,
             auth: auth.map(|(a, b)| (a.into(), b.into())),
             authenticated: false,
-        };
-
-        Ok(client)
+            id: CLIENT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            name: None,
+            db: 0,
+            principal: None,
+            verified: None,
+            context: None,
+            push: None,
+            subscriptions: Default::default(),
+            patterns: Default::default(),
+            multi: None,
+            watched: Vec::new(),
+            dirty: Default::default(),
+            traffic,
+            monitor: None,
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            server_info: None,
+            routes: Default::default(),
+            pushes,
+            push_rx: Some(push_rx),
+        }
     }
 
     pub async fn new<T: tokio::net::ToSocketAddrs>(
         x: T,
         auth: Option<(&str, &str)>,
+    ) -> Result<Client, Error> {
+        let hello = match auth {
+            Some((user, pass)) => Hello::new().auth(user, pass),
+            None => Hello::new(),
+        };
+        Self::connect(x, hello).await
+    }
+
+    /// Connect to a server and perform the `HELLO` handshake using the provided options
+    pub async fn connect<T: tokio::net::ToSocketAddrs>(
+        x: T,
+        hello: Hello,
     ) -> Result<Client, Error> {
         let addrs = tokio::net::lookup_host(x).await?.collect::<Vec<_>>();
+        let auth = hello.auth.as_ref().map(|(a, b)| (a.as_str(), b.as_str()));
         let mut client = Self::new_from_stream(
-            tokio::net::TcpStream::connect(addrs.as_slice()).await?,
+            tokio::net::TcpStream::connect(addrs.as_slice())
+                .await?
+                .into(),
             addrs,
             auth,
         )
         .await?;
 
-        let cmd = Command::new("HELLO").arg("3");
+        client.hello(hello).await?;
+        Ok(client)
+    }
+
+    /// Connect to a server listening on a Unix socket and perform the `HELLO` handshake
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: impl AsRef<std::path::Path>,
+        hello: Hello,
+    ) -> Result<Client, Error> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        let auth = hello.auth.as_ref().map(|(a, b)| (a.as_str(), b.as_str()));
+        let mut client =
+            Self::new_from_stream(stream.into(), vec![([0, 0, 0, 0], 0).into()], auth).await?;
 
-        let cmd = if let Some((user, pass)) = &client.auth {
-            cmd.arg("AUTH").arg(user.as_str()).arg(pass.as_str())
-        } else {
-            cmd
+        client.hello(hello).await?;
+        Ok(client)
+    }
+
+    /// Send `HELLO`, falling back to older protocol versions when the server replies with `NOPROTO`
+    pub async fn hello(&mut self, mut hello: Hello) -> Result<&ServerInfo, Error> {
+        let info = loop {
+            match self.exec(&hello.command().into()).await?.into_result() {
+                Err(e) if e.code == ErrorCode::NoProto && hello.protover > 2 => {
+                    hello.protover -= 1;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+                Ok(info) => break info,
+            }
         };
 
-        // TODO: do something with the HELLO response
-        let info = client.exec(&cmd.into()).await?;
-        if info.as_map().is_none() {
-            return Err(Error::InvalidValue(info));
-        }
+        let info = ServerInfo::try_from(info)?;
+        self.output.set_protocol(info.proto);
+        self.auth = hello.auth;
+        self.name = hello.setname;
+        self.authenticated = true;
 
-        client.authenticated = true;
+        Ok(self.server_info.insert(info))
+    }
 
-        Ok(client)
+    /// Server information returned by the most recent `HELLO`
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.server_info.as_ref()
     }
 
     pub fn addrs(&self) -> &[std::net::SocketAddr] {
         &self.addrs
     }
 
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Database selected using `SELECT`, handlers with more than one database use this to
+    /// decide which one a command applies to
+    pub fn db(&self) -> usize {
+        self.db
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Name of the ACL user the connection is authenticated as
+    pub fn user(&self) -> Option<&str> {
+        self.principal.as_ref().map(|x| x.user())
+    }
+
+    /// Identity of the authenticated user
+    pub fn principal(&self) -> Option<&Principal> {
+        self.principal.as_ref()
+    }
+
+    /// Server-wide state, only available on server-side connections
+    pub fn context(&self) -> Option<&ServerContext> {
+        self.context.as_ref()
+    }
+
+    /// Pub/sub broker shared by all connections to the server, only available on server-side connections
+    pub fn broker(&self) -> Option<&Broker> {
+        self.context.as_ref().map(|x| x.broker())
+    }
+
+    /// Number of channels and patterns this connection is subscribed to
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len() + self.patterns.len()
+    }
+
+    /// Access the underlying decoder, this returns `None` after `subscribe` or `psubscribe` has
+    /// been called because reads are handled by a background task
+    pub fn input(&mut self) -> Option<&mut Input> {
+        match &mut self.input {
+            Reader::Decoder(d) => Some(d),
+            Reader::Routed(_) => None,
+        }
+    }
+
+    pub(crate) async fn readable(&mut self) -> Result<bool, Error> {
+        match &mut self.input {
+            Reader::Decoder(d) => d.readable().await,
+            Reader::Routed(_) => Ok(true),
+        }
+    }
+
+    pub(crate) fn has_buffered_value(&self) -> bool {
+        match &self.input {
+            Reader::Decoder(d) => d.has_buffered_value(),
+            Reader::Routed(_) => false,
+        }
+    }
+
+    /// Read the next reply, pub/sub messages are sent to their `Subscription` and other pushes
+    /// to `Client::pushes`, so a push is never returned in place of a reply
     pub async fn read(&mut self) -> Result<Value, Error> {
-        self.input.decode().await
+        match self.next_reply().await? {
+            // Confirmation for a subscription made using `command` or `exec`, returned in the same
+            // shape as RESP2
+            Value::Push(kind, mut args) => {
+                args.insert(0, kind.into());
+                Ok(Value::Array(args))
+            }
+            value => Ok(value),
+        }
+    }
+
+    // Read values until one of them is a reply or subscription confirmation
+    async fn next_reply(&mut self) -> Result<Value, Error> {
+        loop {
+            let res = match &mut self.input {
+                Reader::Decoder(d) => d.decode().await,
+                Reader::Routed(rx) => match rx.recv().await {
+                    Some(x) => x,
+                    None => Err(Error::Disconnect("connection closed".into())),
+                },
+            };
+
+            #[cfg(feature = "metrics")]
+            if let Err(e) = &res {
+                self.recorder().error(e);
+            }
+
+            // Connections accepted by a server only read commands
+            if self.context.is_some() {
+                return res;
+            }
+
+            if let Some(value) = pubsub::sort(res?, &self.routes, &self.pushes) {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Stream of pushes that aren't pub/sub messages, like those sent using
+    /// `ServerContext::broadcast`. Returns `None` if it has already been taken
+    ///
+    /// The connection is read by a background task from then on, up to 1024 pushes are buffered
+    /// and newer pushes are dropped while the buffer is full
+    pub fn pushes(&mut self) -> Option<Pushes> {
+        let rx = self.push_rx.take()?;
+        self.route_pushes();
+        Some(Pushes { rx })
     }
 
     pub async fn write(&mut self, value: &Value) -> Result<(), Error> {
@@ -77,9 +306,60 @@ impl Client {
     }
 
     pub async fn exec(&mut self, value: &Value) -> Result<Value, Error> {
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+
         self.write(value).await?;
         self.flush().await?;
-        self.read().await
+        let res = self.read().await;
+
+        #[cfg(feature = "metrics")]
+        self.record(value, start.elapsed(), &res);
+
+        res
+    }
+
+    /// Snapshot of the commands sent and bytes transferred by this client
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            connections: 1,
+            connections_received: 1,
+            bytes_read: self.traffic.read(),
+            bytes_written: self.traffic.written(),
+            ..self.metrics.snapshot()
+        }
+    }
+
+    // Connections accepted by a server record errors in the server's metrics
+    #[cfg(feature = "metrics")]
+    fn recorder(&self) -> &metrics::Recorder {
+        match &self.context {
+            Some(ctx) => ctx.recorder(),
+            None => &self.metrics,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record(&self, value: &Value, t: std::time::Duration, res: &Result<Value, Error>) {
+        let name = match value {
+            Value::Array(x) => x.first().and_then(|x| x.as_string()),
+            _ => None,
+        }
+        .map(|x| x.to_ascii_lowercase())
+        .unwrap_or_else(|| "unknown".into());
+
+        let error = match res {
+            Ok(Value::Error(e)) => Some(e.as_str()),
+            Ok(_) => None,
+            Err(_) => Some(""),
+        };
+        if let Some(e) = error {
+            if (name == "auth" || name == "hello") && !e.starts_with("NOPROTO") {
+                self.metrics.auth_failure();
+            }
+        }
+        self.metrics.command(&name, t, error.is_some());
     }
 
     pub async fn command(&mut self, args: impl AsRef<[&str]>) -> Result<Value, Error> {
@@ -90,4 +370,269 @@ impl Client {
             .collect::<Vec<_>>();
         self.exec(&Value::Array(args)).await
     }
+
+    /// Select the database used by the following commands
+    pub async fn select(&mut self, db: usize) -> Result<(), Error> {
+        self.call(Command::new("select").arg(db as i64)).await?;
+        self.db = db;
+        Ok(())
+    }
+
+    /// Send a command and read the reply, error replies are returned as `Error::Reply`
+    pub async fn call(&mut self, command: Command) -> Result<Value, Error> {
+        Ok(self.exec(&command.into()).await?.into_result()?)
+    }
+
+    /// Like `call`, converting the reply to `T`
+    pub async fn call_as<T>(&mut self, command: Command) -> Result<T, Error>
+    where
+        T: std::convert::TryFrom<Value>,
+        Error: From<T::Error>,
+    {
+        Ok(T::try_from(self.call(command).await?)?)
+    }
+
+    fn route_pushes(&mut self) {
+        if let Reader::Routed(_) = self.input {
+            return;
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        if let Reader::Decoder(input) = std::mem::replace(&mut self.input, Reader::Routed(rx)) {
+            tokio::spawn(pubsub::route(
+                input,
+                self.output.protocol(),
+                self.routes.clone(),
+                self.pushes.clone(),
+                tx,
+            ));
+        }
+    }
+
+    async fn confirm(&mut self, kind: &str, n: usize) -> Result<(), Error> {
+        for _ in 0..n {
+            match self.next_reply().await? {
+                Value::Push(k, _) if k == kind => (),
+                Value::Error(e) => return Err(ReplyError::parse(&e).into()),
+                x => return Err(Error::InvalidValue(x)),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn add_subscription(
+        &mut self,
+        kind: &str,
+        names: Vec<String>,
+    ) -> Result<Subscription, Error> {
+        if names.is_empty() {
+            return Err(Error::Internal(format!(
+                "{} requires at least one channel",
+                kind
+            )));
+        }
+
+        self.route_pushes();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(pubsub::PUSH_BUFFER);
+        self.routes
+            .lock()
+            .unwrap()
+            .push(pubsub::Route::new(kind == "psubscribe", &names, tx));
+
+        let cmd = Command::new(kind).with_args(
+            names
+                .iter()
+                .map(|x| Value::from(x.as_str()))
+                .collect::<Vec<_>>(),
+        );
+        self.write(&cmd.into()).await?;
+        self.flush().await?;
+        self.confirm(kind, names.len()).await?;
+
+        Ok(Subscription { rx })
+    }
+
+    async fn remove_subscription(&mut self, kind: &str, names: Vec<String>) -> Result<(), Error> {
+        let pattern = kind == "punsubscribe";
+        let n = {
+            let mut routes = self.routes.lock().unwrap();
+            let mut all = std::collections::BTreeSet::new();
+            for route in routes.iter_mut() {
+                let subs = if pattern {
+                    &mut route.patterns
+                } else {
+                    &mut route.channels
+                };
+
+                if names.is_empty() {
+                    all.extend(std::mem::take(subs));
+                } else {
+                    for name in &names {
+                        subs.remove(name);
+                    }
+                }
+            }
+
+            // The server confirms each channel, or sends a single reply when there were no
+            // subscriptions
+            if names.is_empty() {
+                all.len().max(1)
+            } else {
+                names.len()
+            }
+        };
+
+        let cmd = Command::new(kind).with_args(
+            names
+                .iter()
+                .map(|x| Value::from(x.as_str()))
+                .collect::<Vec<_>>(),
+        );
+        self.write(&cmd.into()).await?;
+        self.flush().await?;
+        self.confirm(kind, n).await
+    }
+
+    /// Subscribe to one or more channels, regular commands can still be executed while the
+    /// returned stream is in use
+    pub async fn subscribe<S: AsRef<str>>(
+        &mut self,
+        channels: impl IntoIterator<Item = S>,
+    ) -> Result<Subscription, Error> {
+        let channels = channels.into_iter().map(|x| x.as_ref().into()).collect();
+        self.add_subscription("subscribe", channels).await
+    }
+
+    /// Subscribe to channels matching one or more glob patterns
+    pub async fn psubscribe<S: AsRef<str>>(
+        &mut self,
+        patterns: impl IntoIterator<Item = S>,
+    ) -> Result<Subscription, Error> {
+        let patterns = patterns.into_iter().map(|x| x.as_ref().into()).collect();
+        self.add_subscription("psubscribe", patterns).await
+    }
+
+    /// Unsubscribe from the given channels, or all channels when empty
+    pub async fn unsubscribe<S: AsRef<str>>(
+        &mut self,
+        channels: impl IntoIterator<Item = S>,
+    ) -> Result<(), Error> {
+        let channels = channels.into_iter().map(|x| x.as_ref().into()).collect();
+        self.remove_subscription("unsubscribe", channels).await
+    }
+
+    /// Unsubscribe from the given patterns, or all patterns when empty
+    pub async fn punsubscribe<S: AsRef<str>>(
+        &mut self,
+        patterns: impl IntoIterator<Item = S>,
+    ) -> Result<(), Error> {
+        let patterns = patterns.into_iter().map(|x| x.as_ref().into()).collect();
+        self.remove_subscription("punsubscribe", patterns).await
+    }
+
+    async fn expect_ok(&mut self) -> Result<(), Error> {
+        let value = self.read().await?;
+        ok_reply(value)
+    }
+
+    async fn unwatch(&mut self) -> Result<(), Error> {
+        self.write(&Command::new("unwatch").into()).await?;
+        self.flush().await?;
+        self.expect_ok().await
+    }
+
+    /// Execute the commands queued by `f` atomically using `MULTI` and `EXEC`
+    ///
+    /// `f` receives the client so it can read values before queueing commands, and its
+    /// return value is passed back along with the replies
+    pub async fn transaction<R, F>(&mut self, f: F) -> Result<(R, Replies), Error>
+    where
+        F: for<'a> FnMut(
+            &'a mut Client,
+            &'a mut Transaction,
+        ) -> futures::future::BoxFuture<'a, Result<R, Error>>,
+    {
+        self.watch_transaction(&[], 0, f).await
+    }
+
+    /// Like `transaction`, but `WATCH`es `keys` before calling `f`. When a watched key is modified
+    /// before `EXEC` the transaction is retried up to `retries` times, after that `Error::Aborted`
+    /// is returned
+    pub async fn watch_transaction<R, F>(
+        &mut self,
+        keys: &[&str],
+        retries: usize,
+        mut f: F,
+    ) -> Result<(R, Replies), Error>
+    where
+        F: for<'a> FnMut(
+            &'a mut Client,
+            &'a mut Transaction,
+        ) -> futures::future::BoxFuture<'a, Result<R, Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            if !keys.is_empty() {
+                let watch = Command::new("watch")
+                    .with_args(keys.iter().map(|x| Value::from(*x)).collect::<Vec<_>>());
+                self.write(&watch.into()).await?;
+                self.flush().await?;
+                self.expect_ok().await?;
+            }
+
+            let mut tx = Transaction::default();
+            let res = match f(self, &mut tx).await {
+                Ok(x) => x,
+                Err(e) => {
+                    if !keys.is_empty() {
+                        self.unwatch().await?;
+                    }
+                    return Err(e);
+                }
+            };
+
+            // MULTI, the queued commands and EXEC are sent together, then all replies are read
+            self.write(&Command::new("multi").into()).await?;
+            let n = tx.commands.len();
+            for command in tx.commands {
+                self.write(&command.into()).await?;
+            }
+            self.write(&Command::new("exec").into()).await?;
+            self.flush().await?;
+
+            // Every reply is read before reporting a failed MULTI, so the connection can still be
+            // used. Errors while queueing are reported by EXEC
+            let multi = self.read().await?;
+            for _ in 0..n {
+                self.read().await?;
+            }
+            let exec = self.read().await?;
+
+            if let Err(e) = ok_reply(multi) {
+                if !keys.is_empty() {
+                    self.unwatch().await?;
+                }
+                return Err(e);
+            }
+
+            match exec {
+                Value::Array(replies) => return Ok((res, Replies(replies))),
+                Value::Null if attempt < retries => attempt += 1,
+                Value::Null => return Err(Error::Aborted),
+                Value::Error(e) => return Err(ReplyError::parse(&e).into()),
+                x => return Err(Error::InvalidValue(x)),
+            }
+        }
+    }
+}
+
+// Check for an `OK` reply
+fn ok_reply(value: Value) -> Result<(), Error> {
+    match value {
+        Value::String(s) if s == "OK" => Ok(()),
+        Value::Error(e) => Err(ReplyError::parse(&e).into()),
+        x => Err(Error::InvalidValue(x)),
+    }
 }
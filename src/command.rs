@@ -1,6 +1,6 @@
 use crate::internal::*;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Command(pub String, pub Vec<Value>);
 
 impl Command {
@@ -14,7 +14,7 @@ impl Command {
     }
 
     pub fn arg(mut self, x: impl Into<Value>) -> Command {
-        self.1.push(x.into().into());
+        self.1.push(x.into());
         self
     }
 
@@ -41,7 +41,7 @@ impl Command {
     }
 
     pub fn pop_front(&mut self) -> Value {
-        if self.1.len() == 0 {
+        if self.1.is_empty() {
             Value::Null
         } else {
             self.1.remove(0)
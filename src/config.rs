@@ -0,0 +1,446 @@
+use crate::internal::*;
+
+use std::time::Duration;
+
+pub(crate) const DEFAULT_DATABASES: usize = 16;
+
+/// Listeners, connection limits, timeouts and socket options used by `Server`
+///
+/// The config can also be loaded from a Redis-style file using `ServerConfig::load`
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Addresses to listen on, either `host:port` or `unix:/path/to/socket`
+    pub bind: Vec<String>,
+
+    /// Protocol version used by new connections until they send `HELLO`
+    pub protocol: i64,
+
+    /// Password for the `default` user
+    pub requirepass: Option<String>,
+
+    /// ACL users, each entry is a username followed by `ACL SETUSER` rules
+    pub users: Vec<String>,
+
+    /// Maximum log level, when set it's applied when the server is built
+    pub log_level: Option<log::LevelFilter>,
+
+    /// Maximum number of open connections, new connections are sent an error and closed
+    pub max_clients: usize,
+
+    /// Number of logical databases that can be selected using `SELECT`
+    pub databases: usize,
+
+    /// Close connections that haven't sent a command for this long, connections subscribed to
+    /// a channel are never considered idle
+    pub idle_timeout: Option<Duration>,
+
+    /// Time allowed to receive the rest of a command once the first bytes have arrived
+    pub read_timeout: Option<Duration>,
+
+    /// Maximum time a single command can take, the handler future is dropped and an error is
+    /// returned when it expires
+    pub command_timeout: Option<Duration>,
+
+    /// Close connections that don't read their replies within this time
+    pub write_timeout: Option<Duration>,
+
+    /// TCP keepalive interval for accepted sockets
+    pub keepalive: Option<Duration>,
+
+    /// Disable Nagle's algorithm on accepted sockets
+    pub nodelay: bool,
+
+    /// Maximum number of pipelined commands executed before replies are flushed
+    pub max_batch: usize,
+
+    /// Maximum number of pub/sub messages and pushes queued for a connection, connections that
+    /// fall further behind are closed
+    pub push_buffer: usize,
+
+    /// Command rate limits
+    pub rate_limits: RateLimits,
+
+    /// Commands that take at least this long are added to the `SLOWLOG`
+    pub slowlog_threshold: Option<Duration>,
+
+    /// Maximum number of `SLOWLOG` entries, the oldest entries are removed first
+    pub slowlog_max_len: usize,
+
+    /// Address of the HTTP listener serving `/metrics`
+    #[cfg(feature = "metrics")]
+    pub metrics_bind: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: Vec::new(),
+            protocol: 3,
+            requirepass: None,
+            users: Vec::new(),
+            log_level: None,
+            max_clients: 10000,
+            databases: DEFAULT_DATABASES,
+            idle_timeout: None,
+            read_timeout: None,
+            command_timeout: None,
+            write_timeout: None,
+            keepalive: None,
+            nodelay: true,
+            max_batch: 1024,
+            push_buffer: 4096,
+            rate_limits: RateLimits::default(),
+            slowlog_threshold: Some(Duration::from_millis(10)),
+            slowlog_max_len: 128,
+            #[cfg(feature = "metrics")]
+            metrics_bind: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> ServerConfig {
+        ServerConfig::default()
+    }
+
+    /// Parse a Redis-style config, each line is a directive followed by its arguments. Empty
+    /// lines and lines starting with `#` are ignored
+    ///
+    /// ```text
+    /// bind 127.0.0.1:6379
+    /// unixsocket /tmp/worm.sock
+    /// maxclients 100
+    /// timeout 300
+    /// command-timeout 500ms
+    /// requirepass secret
+    /// user alice on >password ~cache:* +@read
+    /// loglevel notice
+    /// ratelimit-client 100 200
+    /// ratelimit-user * 1000 2000
+    /// ratelimit-category dangerous 1 5
+    /// ratelimit-mode delay
+    /// slowlog-log-slower-than 10000
+    /// ```
+    pub fn parse(s: &str) -> Result<ServerConfig, Error> {
+        let mut config = ServerConfig::default();
+        for (n, line) in s.lines().enumerate() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            let args =
+                split_line(line).map_err(|e| Error::Config(format!("line {}: {}", n + 1, e)))?;
+            if args.is_empty() {
+                continue;
+            }
+
+            config
+                .set(&args[0], &args[1..])
+                .map_err(|e| Error::Config(format!("line {}: {}", n + 1, e)))?;
+        }
+        Ok(config)
+    }
+
+    /// Load a config file, see `ServerConfig::parse`
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<ServerConfig, Error> {
+        ServerConfig::parse(&std::fs::read_to_string(path)?)
+    }
+
+    // Apply a single config directive
+    fn set(&mut self, name: &str, args: &[String]) -> Result<(), String> {
+        let arg = || match args {
+            [x] => Ok(x.as_str()),
+            _ => Err(format!("expected one argument for '{}'", name)),
+        };
+        let int = || arg()?.parse::<usize>().map_err(|e| e.to_string());
+
+        match name.to_ascii_lowercase().as_str() {
+            "bind" if !args.is_empty() => self.bind.extend(args.iter().cloned()),
+            "unixsocket" => self.bind.push(format!("unix:{}", arg()?)),
+            "protocol" => {
+                self.protocol = match arg()? {
+                    "2" => 2,
+                    "3" => 3,
+                    x => return Err(format!("unsupported protocol version '{}'", x)),
+                }
+            }
+            "requirepass" => self.requirepass = Some(arg()?.into()),
+            "user" if !args.is_empty() => self.users.push(args.join(" ")),
+            "loglevel" => self.log_level = Some(parse_log_level(arg()?)?),
+            "maxclients" => self.max_clients = int()?,
+            "databases" => {
+                self.databases = int()?;
+                if self.databases == 0 {
+                    return Err("databases must be greater than 0".into());
+                }
+            }
+            "timeout" => self.idle_timeout = parse_duration(arg()?)?,
+            "read-timeout" => self.read_timeout = parse_duration(arg()?)?,
+            "command-timeout" => self.command_timeout = parse_duration(arg()?)?,
+            "write-timeout" => self.write_timeout = parse_duration(arg()?)?,
+            "tcp-keepalive" => self.keepalive = parse_duration(arg()?)?,
+            "tcp-nodelay" => {
+                self.nodelay = match arg()? {
+                    "yes" => true,
+                    "no" => false,
+                    x => return Err(format!("expected yes or no, got '{}'", x)),
+                }
+            }
+            "max-batch" => self.max_batch = int()?.max(1),
+            "push-buffer" => self.push_buffer = int()?.max(1),
+            "ratelimit-client" => match args {
+                [rate, burst] => self.rate_limits.client = Some(Limit::parse(rate, burst)?),
+                _ => return Err("expected rate and burst for 'ratelimit-client'".into()),
+            },
+            "ratelimit-user" => match args {
+                [user, rate, burst] => {
+                    let limit = Limit::parse(rate, burst)?;
+                    self.rate_limits.users.insert(user.clone(), limit);
+                }
+                _ => return Err("expected user, rate and burst for 'ratelimit-user'".into()),
+            },
+            "ratelimit-category" => match args {
+                [cat, rate, burst] => {
+                    let limit = Limit::parse(rate, burst)?;
+                    self.rate_limits
+                        .categories
+                        .insert(cat.trim_start_matches('@').to_ascii_lowercase(), limit);
+                }
+                _ => {
+                    return Err("expected category, rate and burst for 'ratelimit-category'".into())
+                }
+            },
+            "ratelimit-mode" => {
+                self.rate_limits.delay = match arg()? {
+                    "error" => false,
+                    "delay" => true,
+                    x => return Err(format!("expected error or delay, got '{}'", x)),
+                }
+            }
+            // In microseconds like Redis, a negative value disables the slow log
+            "slowlog-log-slower-than" => {
+                let us = arg()?.parse::<i64>().map_err(|e| e.to_string())?;
+                self.slowlog_threshold = if us < 0 {
+                    None
+                } else {
+                    Some(Duration::from_micros(us as u64))
+                };
+            }
+            "slowlog-max-len" => self.slowlog_max_len = int()?,
+            #[cfg(feature = "metrics")]
+            "metrics-bind" => self.metrics_bind = Some(arg()?.to_string()),
+            "ratelimit-error" if !args.is_empty() => {
+                self.rate_limits.error = ReplyError::parse(&args.join(" "))
+            }
+            _ => return Err(format!("unknown directive '{}'", name)),
+        }
+
+        Ok(())
+    }
+
+    pub fn bind(mut self, addr: impl Into<String>) -> ServerConfig {
+        self.bind.push(addr.into());
+        self
+    }
+
+    pub fn protocol(mut self, protocol: i64) -> ServerConfig {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn requirepass(mut self, password: impl Into<String>) -> ServerConfig {
+        self.requirepass = Some(password.into());
+        self
+    }
+
+    /// Add an ACL user, `rules` contains the username followed by `ACL SETUSER` rules
+    pub fn user(mut self, rules: impl Into<String>) -> ServerConfig {
+        self.users.push(rules.into());
+        self
+    }
+
+    pub fn log_level(mut self, level: log::LevelFilter) -> ServerConfig {
+        self.log_level = Some(level);
+        self
+    }
+
+    pub fn max_clients(mut self, n: usize) -> ServerConfig {
+        self.max_clients = n;
+        self
+    }
+
+    pub fn databases(mut self, n: usize) -> ServerConfig {
+        self.databases = n;
+        self
+    }
+
+    pub fn idle_timeout(mut self, t: Duration) -> ServerConfig {
+        self.idle_timeout = Some(t);
+        self
+    }
+
+    pub fn read_timeout(mut self, t: Duration) -> ServerConfig {
+        self.read_timeout = Some(t);
+        self
+    }
+
+    pub fn command_timeout(mut self, t: Duration) -> ServerConfig {
+        self.command_timeout = Some(t);
+        self
+    }
+
+    pub fn write_timeout(mut self, t: Duration) -> ServerConfig {
+        self.write_timeout = Some(t);
+        self
+    }
+
+    pub fn keepalive(mut self, t: Duration) -> ServerConfig {
+        self.keepalive = Some(t);
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> ServerConfig {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn max_batch(mut self, n: usize) -> ServerConfig {
+        self.max_batch = n.max(1);
+        self
+    }
+
+    pub fn push_buffer(mut self, n: usize) -> ServerConfig {
+        self.push_buffer = n.max(1);
+        self
+    }
+
+    /// Log commands that take at least `t`, `None` disables the slow log
+    pub fn slowlog_threshold(mut self, t: Option<Duration>) -> ServerConfig {
+        self.slowlog_threshold = t;
+        self
+    }
+
+    /// Maximum number of `SLOWLOG` entries
+    pub fn slowlog_max_len(mut self, n: usize) -> ServerConfig {
+        self.slowlog_max_len = n;
+        self
+    }
+
+    /// Serve metrics at `http://<addr>/metrics`
+    #[cfg(feature = "metrics")]
+    pub fn metrics_bind(mut self, addr: impl Into<String>) -> ServerConfig {
+        self.metrics_bind = Some(addr.into());
+        self
+    }
+
+    pub fn rate_limits(mut self, limits: RateLimits) -> ServerConfig {
+        self.rate_limits = limits;
+        self
+    }
+
+    // Apply socket options to an accepted connection
+    pub(crate) fn configure(&self, socket: &Stream) -> Result<(), Error> {
+        let socket = match socket {
+            Stream::Tcp(x) => x,
+            #[cfg(unix)]
+            Stream::Unix(_) => return Ok(()),
+            Stream::Memory(_) => return Ok(()),
+        };
+
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(t) = self.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(t);
+            socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+// Split a config line into arguments, double quotes can be used for arguments containing spaces
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => continue,
+            '"' => {
+                let mut arg = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => arg.extend(chars.next()),
+                        Some(c) => arg.push(c),
+                        None => return Err("unterminated quote".into()),
+                    }
+                }
+                args.push(arg);
+            }
+            c => {
+                let mut arg = c.to_string();
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    arg.push(*c);
+                    chars.next();
+                }
+                args.push(arg);
+            }
+        }
+    }
+    Ok(args)
+}
+
+// Durations are in seconds unless they end with `ms`, `s` or `m`, zero disables the timeout
+fn parse_duration(s: &str) -> Result<Option<Duration>, String> {
+    let (n, scale) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 1)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1000)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60_000)
+    } else {
+        (s, 1000)
+    };
+
+    let n = n
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    if n == 0 {
+        return Ok(None);
+    }
+
+    match n.checked_mul(scale) {
+        Some(ms) => Ok(Some(Duration::from_millis(ms))),
+        None => Err(format!("duration '{}' is too large", s)),
+    }
+}
+
+// Accepts the Redis log levels as well as the names used by the `log` crate
+fn parse_log_level(s: &str) -> Result<log::LevelFilter, String> {
+    let level = match s.to_ascii_lowercase().as_str() {
+        "nothing" | "off" => log::LevelFilter::Off,
+        "error" => log::LevelFilter::Error,
+        "warning" | "warn" | "notice" => log::LevelFilter::Warn,
+        "verbose" | "info" => log::LevelFilter::Info,
+        "debug" => log::LevelFilter::Debug,
+        "trace" => log::LevelFilter::Trace,
+        _ => return Err(format!("invalid log level '{}'", s)),
+    };
+    Ok(level)
+}
+
+// Run `f`, failing with `Error::Disconnect` if it doesn't complete within `t`
+pub(crate) async fn timeout<T>(
+    t: Option<Duration>,
+    what: &str,
+    f: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match t {
+        Some(t) => match tokio::time::timeout(t, f).await {
+            Ok(x) => x,
+            Err(_) => Err(Error::Disconnect(format!("{} timed out", what))),
+        },
+        None => f.await,
+    }
+}
@@ -0,0 +1,453 @@
+use crate::internal::*;
+
+use std::collections::BTreeMap;
+
+use std::time::Instant;
+
+// State of a connection shown by `CLIENT LIST`, copied from the `Client` whenever it runs a command
+#[derive(Debug, Clone)]
+struct Details {
+    name: Option<String>,
+    user: Option<String>,
+    cmd: Option<String>,
+    sub: usize,
+    psub: usize,
+    db: usize,
+    multi: i64,
+    watch: usize,
+    resp: i64,
+}
+
+impl Details {
+    fn new(client: &Client, cmd: Option<String>) -> Details {
+        Details {
+            name: client.name().map(String::from),
+            user: client.user().map(String::from),
+            cmd,
+            sub: client.subscriptions.len(),
+            psub: client.patterns.len(),
+            db: client.db(),
+            multi: client
+                .multi
+                .as_ref()
+                .map(|x| x.commands.len() as i64)
+                .unwrap_or(-1),
+            watch: client.watched.len(),
+            resp: client.output.protocol(),
+        }
+    }
+}
+
+// Description of a connection in the format used by `CLIENT LIST` and `CLIENT INFO`
+fn describe(
+    info: &ConnectionInfo,
+    details: &Details,
+    created: Instant,
+    active: Instant,
+    traffic: &stream::Traffic,
+) -> String {
+    format!(
+        "id={} addr={} name={} user={} age={} idle={} db={} sub={} psub={} multi={} watch={} tot-net-in={} tot-net-out={} cmd={} resp={}",
+        info.id,
+        info.addr,
+        details.name.as_deref().unwrap_or_default(),
+        details.user.as_deref().unwrap_or_default(),
+        created.elapsed().as_secs(),
+        active.elapsed().as_secs(),
+        details.db,
+        details.sub,
+        details.psub,
+        details.multi,
+        details.watch,
+        traffic.read(),
+        traffic.written(),
+        details.cmd.as_deref().unwrap_or("NULL"),
+        details.resp,
+    )
+}
+
+// Description of the current connection, as returned by `CLIENT INFO`
+pub(crate) fn client_info(client: &Client) -> String {
+    if let Some(ctx) = client.context() {
+        ctx.update(client, None);
+        if let Some(s) = ctx.describe(client.id()) {
+            return s;
+        }
+    }
+
+    let info = ConnectionInfo {
+        id: client.id(),
+        addr: client.addrs()[0],
+    };
+    let now = Instant::now();
+    describe(
+        &info,
+        &Details::new(client, None),
+        now,
+        now,
+        &client.traffic,
+    )
+}
+
+// Bytes read and written by open connections
+fn net(connections: &BTreeMap<u64, Connection>) -> (u64, u64) {
+    connections.values().fold((0, 0), |(r, w), conn| {
+        (r + conn.traffic.read(), w + conn.traffic.written())
+    })
+}
+
+/// Selects connections for `CLIENT KILL`, every field that is set has to match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientFilter {
+    pub id: Option<u64>,
+    pub addr: Option<std::net::SocketAddr>,
+    pub user: Option<String>,
+
+    /// Connection to leave out, usually the one sending the command
+    pub skip: Option<u64>,
+}
+
+impl ClientFilter {
+    fn matches(&self, info: &ConnectionInfo, details: &Details) -> bool {
+        self.id.map(|x| x == info.id).unwrap_or(true)
+            && self.addr.map(|x| x == info.addr).unwrap_or(true)
+            && self
+                .user
+                .as_ref()
+                .map(|x| details.user.as_ref() == Some(x))
+                .unwrap_or(true)
+            && self.skip != Some(info.id)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pause {
+    until: Instant,
+    all: bool,
+}
+
+/// Connection registered with a running server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub addr: std::net::SocketAddr,
+}
+
+struct Connection {
+    info: ConnectionInfo,
+    tx: pubsub::Sender,
+    traffic: std::sync::Arc<stream::Traffic>,
+    created: Instant,
+    active: Instant,
+    details: Details,
+    kill: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    broker: Broker,
+    acl: Acl,
+    limiter: ratelimit::RateLimiter,
+    stats: info::Stats,
+    monitors: monitor::Monitors,
+    slowlog: slowlog::SlowLog,
+    databases: Databases,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Recorder,
+    watchers: transaction::Watchers,
+    connections: std::sync::Mutex<BTreeMap<u64, Connection>>,
+    pause: std::sync::Mutex<Option<Pause>>,
+    unpause: tokio::sync::Notify,
+}
+
+// Number of databases accepted by `SELECT`, set from the config when the server starts
+struct Databases(std::sync::atomic::AtomicUsize);
+
+impl Default for Databases {
+    fn default() -> Databases {
+        Databases(config::DEFAULT_DATABASES.into())
+    }
+}
+
+/// Handle to server-wide state, available to handlers using `Client::context`
+#[derive(Clone, Default)]
+pub struct ServerContext(std::sync::Arc<Shared>);
+
+impl ServerContext {
+    pub fn broker(&self) -> &Broker {
+        &self.0.broker
+    }
+
+    /// Users and their permissions
+    pub fn acl(&self) -> &Acl {
+        &self.0.acl
+    }
+
+    /// Number of logical databases, see `ServerConfig::databases`
+    pub fn databases(&self) -> usize {
+        self.0
+            .databases
+            .0
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_databases(&self, n: usize) {
+        self.0
+            .databases
+            .0
+            .store(n, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Parse a database index argument, for handlers implementing commands like `MOVE` and
+    /// `SWAPDB`
+    pub fn db_index(&self, value: &Value) -> Result<usize, ReplyError> {
+        match value.as_int() {
+            Some(i) if i >= 0 && (i as usize) < self.databases() => Ok(i as usize),
+            Some(_) => Err(ReplyError::err("DB index is out of range")),
+            None => Err(ReplyError::err("value is not an integer or out of range")),
+        }
+    }
+
+    pub(crate) fn limiter(&self) -> &ratelimit::RateLimiter {
+        &self.0.limiter
+    }
+
+    pub(crate) fn stats(&self) -> &info::Stats {
+        &self.0.stats
+    }
+
+    pub(crate) fn monitors(&self) -> &monitor::Monitors {
+        &self.0.monitors
+    }
+
+    pub(crate) fn slowlog(&self) -> &slowlog::SlowLog {
+        &self.0.slowlog
+    }
+
+    /// Built-in `INFO` sections
+    pub(crate) fn info(&self, info: &mut Info) {
+        let connections = self.0.connections.lock().unwrap();
+        self.0
+            .stats
+            .info(info, connections.len(), net(&connections));
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn recorder(&self) -> &metrics::Recorder {
+        &self.0.metrics
+    }
+
+    /// Snapshot of the server's metrics, see `Metrics::render` for the Prometheus text format
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Metrics {
+        use std::sync::atomic::Ordering;
+
+        let stats = &self.0.stats;
+        let connections = self.0.connections.lock().unwrap();
+        let (read, written) = net(&connections);
+        Metrics {
+            connections: connections.len() as u64,
+            connections_received: stats.connections_received.load(Ordering::Relaxed),
+            rejected_connections: stats.rejected_connections.load(Ordering::Relaxed),
+            bytes_read: stats.net_input_bytes.load(Ordering::Relaxed) + read,
+            bytes_written: stats.net_output_bytes.load(Ordering::Relaxed) + written,
+            ..self.0.metrics.snapshot()
+        }
+    }
+
+    /// Add a connection to the registry, the receiver completes when the connection is killed
+    pub(crate) fn register(
+        &self,
+        client: &Client,
+        tx: pubsub::Sender,
+    ) -> (ConnectionInfo, tokio::sync::oneshot::Receiver<()>) {
+        let info = ConnectionInfo {
+            id: client.id(),
+            addr: client.addrs()[0],
+        };
+        let (kill, killed) = tokio::sync::oneshot::channel();
+        let now = Instant::now();
+        self.0.connections.lock().unwrap().insert(
+            info.id,
+            Connection {
+                info: info.clone(),
+                tx,
+                traffic: client.traffic.clone(),
+                created: now,
+                active: now,
+                details: Details::new(client, None),
+                kill: Some(kill),
+            },
+        );
+        info::Stats::incr(&self.0.stats.connections_received, 1);
+        (info, killed)
+    }
+
+    /// Refresh the registry entry for a connection, `cmd` is set when it starts a new command
+    pub(crate) fn update(&self, client: &Client, cmd: Option<&str>) {
+        let mut connections = self.0.connections.lock().unwrap();
+        if let Some(conn) = connections.get_mut(&client.id()) {
+            let cmd = match cmd {
+                Some(cmd) => {
+                    conn.active = Instant::now();
+                    Some(cmd.to_string())
+                }
+                None => conn.details.cmd.take(),
+            };
+            conn.details = Details::new(client, cmd);
+        }
+    }
+
+    // Description of a registered connection
+    fn describe(&self, id: u64) -> Option<String> {
+        let connections = self.0.connections.lock().unwrap();
+        connections.get(&id).map(|conn| {
+            describe(
+                &conn.info,
+                &conn.details,
+                conn.created,
+                conn.active,
+                &conn.traffic,
+            )
+        })
+    }
+
+    /// Describe open connections, one per line, in the format used by `CLIENT LIST`. When `ids`
+    /// is empty every connection is included
+    pub fn client_list(&self, ids: &[u64]) -> String {
+        let connections = self.0.connections.lock().unwrap();
+        connections
+            .values()
+            .filter(|conn| ids.is_empty() || ids.contains(&conn.info.id))
+            .map(|conn| {
+                describe(
+                    &conn.info,
+                    &conn.details,
+                    conn.created,
+                    conn.active,
+                    &conn.traffic,
+                ) + "\n"
+            })
+            .collect()
+    }
+
+    /// Close every connection matching `filter`, returns the number of connections closed
+    ///
+    /// A connection is closed as soon as its task is next polled, a command it's executing is
+    /// cancelled
+    pub fn kill(&self, filter: &ClientFilter) -> usize {
+        let mut connections = self.0.connections.lock().unwrap();
+        connections
+            .values_mut()
+            .filter(|conn| filter.matches(&conn.info, &conn.details))
+            .filter_map(|conn| conn.kill.take())
+            .map(|kill| kill.send(()).is_ok())
+            .filter(|sent| *sent)
+            .count()
+    }
+
+    /// Stop executing commands for `t`, when `all` is false only commands in the `write`
+    /// category are paused. `CLIENT` commands are never paused
+    pub fn pause(&self, t: std::time::Duration, all: bool) {
+        *self.0.pause.lock().unwrap() = Some(Pause {
+            until: Instant::now() + t,
+            all,
+        });
+    }
+
+    pub fn unpause(&self) {
+        *self.0.pause.lock().unwrap() = None;
+        self.0.unpause.notify_waiters();
+    }
+
+    // End of the pause affecting a command, if there is one
+    pub(crate) fn paused(&self, write: bool) -> Option<Instant> {
+        match *self.0.pause.lock().unwrap() {
+            Some(p) if (p.all || write) && p.until > Instant::now() => Some(p.until),
+            _ => None,
+        }
+    }
+
+    // Wait until a command is no longer paused
+    pub(crate) async fn wait_unpaused(&self, write: bool) {
+        loop {
+            // Created before checking so an `unpause` in between isn't missed
+            let unpaused = self.0.unpause.notified();
+            let until = match self.paused(write) {
+                Some(x) => x,
+                None => return,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(until.into()) => (),
+                _ = unpaused => (),
+            }
+        }
+    }
+
+    pub(crate) fn unregister(&self, client: &mut Client) {
+        self.unwatch(client);
+        if let Some(conn) = self.0.connections.lock().unwrap().remove(&client.id()) {
+            info::Stats::incr(&self.0.stats.net_input_bytes, conn.traffic.read());
+            info::Stats::incr(&self.0.stats.net_output_bytes, conn.traffic.written());
+        }
+        self.0.broker.remove_client(client.id());
+        self.0.limiter.remove_client(client.id());
+        self.0.monitors.remove(client.id());
+    }
+
+    pub(crate) fn watch(&self, client: &mut Client, key: Value) {
+        let key = (client.db(), key);
+        self.0
+            .watchers
+            .watch(client.id(), &client.dirty, key.clone());
+        client.watched.push(key);
+    }
+
+    pub(crate) fn unwatch(&self, client: &mut Client) {
+        self.0.watchers.unwatch(client.id(), &client.watched);
+        client.watched.clear();
+        client
+            .dirty
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Report that `key` has been modified in database `db`, aborting transactions on any
+    /// connection watching it. Keys are watched in the database selected when `WATCH` was sent
+    pub fn touch(&self, db: usize, key: &Value) {
+        self.0.watchers.touch(db, key)
+    }
+
+    /// List all open connections
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.0.connections.lock().unwrap();
+        connections.values().map(|x| x.info.clone()).collect()
+    }
+
+    pub fn connection(&self, id: u64) -> Option<ConnectionInfo> {
+        let connections = self.0.connections.lock().unwrap();
+        connections.get(&id).map(|x| x.info.clone())
+    }
+
+    /// Queue a push message for the connection with the given id, returns `false` if the
+    /// connection no longer exists
+    ///
+    /// Pushes are written by the connection between commands, so they never interleave with replies.
+    /// RESP2 connections only receive pub/sub messages, other pushes are dropped
+    pub fn send(&self, id: u64, kind: impl Into<String>, values: Vec<Value>) -> bool {
+        let connections = self.0.connections.lock().unwrap();
+        match connections.get(&id) {
+            Some(conn) => conn.tx.send(Value::Push(kind.into(), values)),
+            None => false,
+        }
+    }
+
+    /// Queue a push message for every connection, returns the number of connections it was sent to
+    pub fn broadcast(&self, kind: impl Into<String>, values: Vec<Value>) -> usize {
+        let msg = Value::Push(kind.into(), values);
+        let connections = self.0.connections.lock().unwrap();
+        connections
+            .values()
+            .filter(|conn| conn.tx.send(msg.clone()))
+            .count()
+    }
+}
@@ -1,5 +1,62 @@
 use crate::internal::*;
 
+// Aggregates are read one value at a time, this limits the memory reserved up front for a length
+// sent by the peer
+const MAX_RESERVE: usize = 1024;
+
+// Number of values following an aggregate header of `len` entries, with `per_entry` values each
+fn values(len: i64, per_entry: i64) -> Result<i64, Error> {
+    match len.checked_mul(per_entry) {
+        Some(n) if n >= 0 => Ok(n),
+        _ => Err(Error::InvalidValue(Value::Int(len))),
+    }
+}
+
+// Returns the length of the first complete frame in `buf`, or `None` if more data is needed
+fn frame_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    let end = match buf.windows(2).position(|x| x == b"\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let header = || {
+        std::str::from_utf8(&buf[1..end])
+            .ok()
+            .and_then(|x| x.parse::<i64>().ok())
+            .ok_or(Error::InvalidByte(None))
+    };
+    let mut n = end + 2;
+
+    let count = match buf[0] {
+        b'$' | b'!' | b'=' => {
+            let len = header()?;
+            if len < 0 {
+                return Ok(Some(n));
+            }
+
+            n = n
+                .checked_add(len as usize)
+                .and_then(|x| x.checked_add(2))
+                .ok_or(Error::InvalidValue(Value::Int(len)))?;
+            return Ok(if buf.len() >= n { Some(n) } else { None });
+        }
+        b'*' | b'~' | b'>' => header()?,
+        b'%' => values(header()?, 2)?,
+
+        // Attributes are followed by the value they describe
+        b'|' => values(header()?, 2)? + 1,
+        _ => return Ok(Some(n)),
+    };
+
+    for _ in 0..count.max(0) {
+        match frame_len(&buf[n..])? {
+            Some(len) => n += len,
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(n))
+}
+
 pub struct Decoder<T> {
     pub input: BufReader<T>,
 }
@@ -26,6 +83,26 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
         self.input.into_inner()
     }
 
+    /// Wait until there is data available to decode without consuming any input, returns `false`
+    /// once the stream has been closed
+    pub async fn readable(&mut self) -> Result<bool, Error> {
+        let input = &mut self.input;
+        let ready = futures::future::poll_fn(|cx| {
+            std::pin::Pin::new(&mut *input)
+                .poll_fill_buf(cx)
+                .map_ok(|x| !x.is_empty())
+        })
+        .await?;
+        Ok(ready)
+    }
+
+    /// Returns `true` when a complete value is already buffered, so `decode` will not wait on the
+    /// underlying reader. Invalid values are also reported as buffered, `decode` returns the error
+    pub fn has_buffered_value(&self) -> bool {
+        let buf = self.input.buffer();
+        !buf.is_empty() && !matches!(frame_len(buf), Ok(None))
+    }
+
     fn skip(&mut self, n: usize) {
         AsyncBufRead::consume(std::pin::Pin::new(&mut self.input), n);
     }
@@ -46,6 +123,19 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
         Ok(dest)
     }
 
+    // Read `len` bytes, the buffer grows as data arrives instead of trusting the length up front
+    async fn get_bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut dest = Vec::with_capacity(len.min(MAX_RESERVE));
+        (&mut self.input)
+            .take(len as u64)
+            .read_to_end(&mut dest)
+            .await?;
+        if dest.len() < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(dest)
+    }
+
     async fn get_number<F: std::str::FromStr>(&mut self) -> Result<F, Error>
     where
         Error: From<F::Err>,
@@ -65,14 +155,19 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
     }
 
     pub async fn read_blob_string(&mut self) -> Result<Value, Error> {
-        let len = self.get_number::<usize>().await?;
+        // RESP2 null bulk string
+        let len = self.get_number::<i64>().await?;
+        if len < 0 {
+            return Ok(Value::Null);
+        }
+
+        let len = len as usize;
         if len == 0 {
             self.skip_crlf();
             return Ok(Value::String(String::new()));
         }
 
-        let mut dest = vec![0; len];
-        self.input.read_exact(&mut dest).await?;
+        let dest = self.get_bytes(len).await?;
         self.skip_crlf();
         match String::from_utf8(dest) {
             Ok(s) => Ok(Value::String(s)),
@@ -106,8 +201,7 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
 
     pub async fn read_blob_error(&mut self) -> Result<Value, Error> {
         let len = self.get_number::<usize>().await?;
-        let mut dest = vec![0; len];
-        self.input.read_exact(&mut dest).await?;
+        let dest = self.get_bytes(len).await?;
         self.skip_crlf();
         match String::from_utf8(dest) {
             Ok(s) => Ok(Value::Error(s)),
@@ -120,12 +214,15 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
     pub async fn read_verbatim_string(&mut self) -> Result<Value, Error> {
         let len = self.get_number::<usize>().await?;
 
-        // TODO: do something with the string type, here we are skipping it
-        self.skip(4);
-
-        let mut dest = vec![0; len];
-        self.input.read_exact(&mut dest).await?;
+        // The length includes the three byte format and the `:` that follows it
+        let mut dest = self.get_bytes(len).await?;
         self.skip_crlf();
+        if dest.get(3) != Some(&b':') {
+            return Err(Error::InvalidByte(dest.get(3).copied()));
+        }
+
+        // TODO: do something with the string type, here we are skipping it
+        dest.drain(..4);
         match String::from_utf8(dest) {
             Ok(s) => Ok(Value::String(s)),
             Err(e) => Ok(Value::Bytes(e.into_bytes())),
@@ -138,9 +235,15 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
     }
 
     pub async fn read_array(&mut self) -> Result<Value, Error> {
-        let len = self.get_number::<usize>().await?;
+        // RESP2 null array
+        let len = self.get_number::<i64>().await?;
+        if len < 0 {
+            return Ok(Value::Null);
+        }
+
+        let len = len as usize;
 
-        let mut arr = Vec::with_capacity(len);
+        let mut arr = Vec::with_capacity(len.min(MAX_RESERVE));
 
         for _ in 0..len {
             let value = self.decode().await?;
@@ -151,7 +254,8 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
     }
 
     pub async fn read_map(&mut self) -> Result<Value, Error> {
-        let len = self.get_number::<usize>().await?;
+        let len = self.get_number::<i64>().await?;
+        let len = values(len, 2)? as usize / 2;
 
         let mut map = Map::new();
 
@@ -166,7 +270,8 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
 
     // Ignore attributes and read next value
     async fn skip_attribute(&mut self) -> Result<Value, Error> {
-        let len = self.get_number::<usize>().await?;
+        let len = self.get_number::<i64>().await?;
+        let len = values(len, 2)? as usize / 2;
 
         let mut map = Map::new();
 
@@ -194,14 +299,15 @@ impl<T: AsyncRead + Unpin + Send> Decoder<T> {
 
     pub async fn read_push(&mut self) -> Result<Value, Error> {
         let len = self.get_number::<usize>().await?;
+        let len = len.checked_sub(1).ok_or(Error::InvalidByte(None))?;
         let kind = match self.decode().await? {
             Value::String(s) => s,
             _ => return Err(Error::InvalidByte(None)),
         };
 
-        let mut arr = Vec::with_capacity(len - 1);
+        let mut arr = Vec::with_capacity(len.min(MAX_RESERVE));
 
-        for _ in 0..len - 1 {
+        for _ in 0..len {
             let value = self.decode().await?;
             arr.push(value);
         }
@@ -2,6 +2,7 @@ use crate::internal::*;
 
 pub struct Encoder<T> {
     pub output: BufWriter<T>,
+    protocol: i64,
 }
 
 unsafe impl<T> Send for Encoder<T> {}
@@ -11,9 +12,23 @@ impl<T: Unpin + Send + AsyncWrite> Encoder<T> {
     pub fn new(x: T) -> Self {
         Encoder {
             output: BufWriter::new(x),
+            protocol: 3,
         }
     }
 
+    /// RESP version used when encoding values, either 2 or 3
+    pub fn protocol(&self) -> i64 {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: i64) {
+        self.protocol = protocol;
+    }
+
+    fn is_resp2(&self) -> bool {
+        self.protocol < 3
+    }
+
     pub fn get_ref(&self) -> &T {
         self.output.get_ref()
     }
@@ -39,11 +54,19 @@ impl<T: Unpin + Send + AsyncWrite> Encoder<T> {
     }
 
     pub async fn write_null(&mut self) -> Result<(), Error> {
-        self.output.write_all(b"_").await?;
+        if self.is_resp2() {
+            self.output.write_all(b"$-1").await?;
+        } else {
+            self.output.write_all(b"_").await?;
+        }
         self.write_crlf().await
     }
 
     pub async fn write_bool(&mut self, b: &bool) -> Result<(), Error> {
+        if self.is_resp2() {
+            return self.write_int(&(*b as i64)).await;
+        }
+
         if *b {
             self.output.write_all(b"#t").await?;
         } else {
@@ -59,18 +82,35 @@ impl<T: Unpin + Send + AsyncWrite> Encoder<T> {
     }
 
     pub async fn write_float(&mut self, i: &Float) -> Result<(), Error> {
+        if self.is_resp2() {
+            return self.write_string(i.to_string().as_bytes()).await;
+        }
+
         self.output.write_all(b",").await?;
         self.output.write_all(i.to_string().as_bytes()).await?;
         self.write_crlf().await
     }
 
     pub async fn write_big_number(&mut self, i: &str) -> Result<(), Error> {
+        if self.is_resp2() {
+            return self.write_string(i.as_bytes()).await;
+        }
+
         self.output.write_all(b"(").await?;
         self.output.write_all(i.as_bytes()).await?;
         self.write_crlf().await
     }
 
     pub async fn write_error(&mut self, e: &str) -> Result<(), Error> {
+        // RESP2 has no blob errors, so line breaks are replaced with spaces
+        if self.is_resp2() {
+            self.output.write_all(b"-").await?;
+            self.output
+                .write_all(e.replace(&['\r', '\n'][..], " ").as_bytes())
+                .await?;
+            return self.write_crlf().await;
+        }
+
         if e.contains('\r') || e.contains('\n') {
             self.output
                 .write_all(format!("!{}", e.len()).as_bytes())
@@ -117,6 +157,10 @@ impl<T: Unpin + Send + AsyncWrite> Encoder<T> {
     }
 
     pub async fn write_map_header(&mut self, n: usize) -> Result<(), Error> {
+        if self.is_resp2() {
+            return self.write_array_header(n * 2).await;
+        }
+
         self.write_length('%', n).await?;
         Ok(())
     }
@@ -133,6 +177,10 @@ impl<T: Unpin + Send + AsyncWrite> Encoder<T> {
     }
 
     pub async fn write_set_header(&mut self, n: usize) -> Result<(), Error> {
+        if self.is_resp2() {
+            return self.write_array_header(n).await;
+        }
+
         self.write_length('~', n).await?;
         Ok(())
     }
@@ -147,13 +195,25 @@ impl<T: Unpin + Send + AsyncWrite> Encoder<T> {
         Ok(())
     }
 
-    pub async fn write_push_header(&mut self, kind: impl AsRef<str>, len: usize) -> Result<(), Error> {
-        self.write_length('>', len + 1).await?;
+    pub async fn write_push_header(
+        &mut self,
+        kind: impl AsRef<str>,
+        len: usize,
+    ) -> Result<(), Error> {
+        if self.is_resp2() {
+            self.write_array_header(len + 1).await?;
+        } else {
+            self.write_length('>', len + 1).await?;
+        }
         self.write_string(kind.as_ref().as_bytes()).await?;
         Ok(())
     }
 
-    pub async fn write_push(&mut self, kind: impl AsRef<str>, values: &[Value]) -> Result<(), Error> {
+    pub async fn write_push(
+        &mut self,
+        kind: impl AsRef<str>,
+        values: &[Value],
+    ) -> Result<(), Error> {
         self.write_push_header(kind, values.len()).await?;
         for a in values {
             self.encode(a).await?;
@@ -26,25 +26,171 @@ pub enum Error {
     #[error("Error: {0}")]
     Error(#[from] anyhow::Error),
 
-    #[error("Used to signal when streaming data has been written")]
-    Done,
+    #[error("Transaction aborted, a watched key was modified")]
+    Aborted,
+
+    #[error("Invalid config: {0}")]
+    Config(String),
+
+    #[error("Reply error: {0}")]
+    Reply(#[from] ReplyError),
+}
+
+/// Error code, the first word of an error reply
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorCode {
+    Err,
+    WrongType,
+    NoAuth,
+    NoPerm,
+    NoProto,
+    Busy,
+    Moved,
+    Ask,
+    Custom(String),
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorCode::Err => "ERR",
+            ErrorCode::WrongType => "WRONGTYPE",
+            ErrorCode::NoAuth => "NOAUTH",
+            ErrorCode::NoPerm => "NOPERM",
+            ErrorCode::NoProto => "NOPROTO",
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::Moved => "MOVED",
+            ErrorCode::Ask => "ASK",
+            ErrorCode::Custom(s) => s.as_str(),
+        }
+    }
+}
+
+impl From<&str> for ErrorCode {
+    fn from(s: &str) -> ErrorCode {
+        match s {
+            "ERR" => ErrorCode::Err,
+            "WRONGTYPE" => ErrorCode::WrongType,
+            "NOAUTH" => ErrorCode::NoAuth,
+            "NOPERM" => ErrorCode::NoPerm,
+            "NOPROTO" => ErrorCode::NoProto,
+            "BUSY" => ErrorCode::Busy,
+            "MOVED" => ErrorCode::Moved,
+            "ASK" => ErrorCode::Ask,
+            s => ErrorCode::Custom(s.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error reply sent by a server, made up of an error code and a message
+///
+/// Handlers can return a `ReplyError` as an error, it's sent to the client as an error reply
+/// instead of being treated as an internal error
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub struct ReplyError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ReplyError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> ReplyError {
+        ReplyError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Generic `ERR` reply
+    pub fn err(message: impl Into<String>) -> ReplyError {
+        ReplyError::new(ErrorCode::Err, message)
+    }
+
+    pub fn wrong_type() -> ReplyError {
+        ReplyError::new(
+            ErrorCode::WrongType,
+            "Operation against a key holding the wrong kind of value",
+        )
+    }
+
+    /// Parse an error reply, the first word is used as the code if it's uppercase, otherwise
+    /// the code is `ERR`
+    pub fn parse(s: &str) -> ReplyError {
+        let (code, message) = match s.find(' ') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+
+        let is_code = !code.is_empty()
+            && code
+                .bytes()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'_' || c == b'-');
+        if !is_code {
+            return ReplyError::err(s);
+        }
+
+        ReplyError::new(ErrorCode::from(code), message)
+    }
+
+    /// Slot and address of a `MOVED` or `ASK` redirect
+    pub fn redirect(&self) -> Option<(u16, &str)> {
+        match self.code {
+            ErrorCode::Moved | ErrorCode::Ask => {
+                let mut parts = self.message.split_whitespace();
+                let slot = parts.next()?.parse().ok()?;
+                let addr = parts.next()?;
+                Some((slot, addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.message.is_empty() {
+            return write!(f, "{}", self.code);
+        }
+
+        write!(f, "{} {}", self.code, self.message)
+    }
+}
+
+impl From<ReplyError> for Value {
+    fn from(x: ReplyError) -> Value {
+        Value::Error(x.to_string())
+    }
+}
+
+impl From<std::convert::Infallible> for Error {
+    fn from(x: std::convert::Infallible) -> Error {
+        match x {}
+    }
 }
 
 impl Error {
-    pub fn disconnect(s: impl Into<String>) -> Result<Value, anyhow::Error> {
+    pub fn disconnect<T>(s: impl Into<String>) -> Result<T, anyhow::Error> {
         Err(Error::Disconnect(s.into()).into())
     }
 
-    pub fn invalid_args(
+    pub fn invalid_args<T: From<Value>>(
         cmd: impl AsRef<str>,
         got: usize,
         expected: usize,
-    ) -> Result<Value, anyhow::Error> {
-        Ok(Value::Error(format!(
-            "ERR wrong number of arguments for {} command, got {} but expected {}",
-            cmd.as_ref(),
-            got,
-            expected
-        )))
+    ) -> Result<T, anyhow::Error> {
+        Ok(T::from(
+            ReplyError::err(format!(
+                "wrong number of arguments for {} command, got {} but expected {}",
+                cmd.as_ref(),
+                got,
+                expected
+            ))
+            .into(),
+        ))
     }
 }
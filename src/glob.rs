@@ -0,0 +1,93 @@
+/// Redis-style glob matching, supports `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let s = s.as_bytes();
+
+    let (mut p, mut i) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, i));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    i += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(&pattern[p..], s[i]) {
+                        if matched {
+                            p += next;
+                            i += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == s[i] {
+                        p += 2;
+                        i += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == s[i] {
+                        p += 1;
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // Mismatch, retry from the last `*` consuming one more byte
+        match backtrack {
+            Some((bp, bi)) => {
+                backtrack = Some((bp, bi + 1));
+                p = bp + 1;
+                i = bi + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// Returns whether `c` matches the class at the start of `pattern` and the length of the class
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut j = 1;
+    let negate = pattern.get(j) == Some(&b'^');
+    if negate {
+        j += 1;
+    }
+
+    let mut matched = false;
+    while j < pattern.len() && pattern[j] != b']' {
+        if pattern[j] == b'\\' && j + 1 < pattern.len() {
+            j += 1;
+            matched |= pattern[j] == c;
+        } else if j + 2 < pattern.len() && pattern[j + 1] == b'-' && pattern[j + 2] != b']' {
+            let (a, b) = (
+                pattern[j].min(pattern[j + 2]),
+                pattern[j].max(pattern[j + 2]),
+            );
+            matched |= c >= a && c <= b;
+            j += 2;
+        } else {
+            matched |= pattern[j] == c;
+        }
+        j += 1;
+    }
+
+    if j >= pattern.len() {
+        return None;
+    }
+
+    Some((matched != negate, j + 1))
+}
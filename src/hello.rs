@@ -0,0 +1,132 @@
+use crate::internal::*;
+
+/// Options sent to the server in the `HELLO` handshake
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub protover: i64,
+    pub auth: Option<(String, String)>,
+    pub setname: Option<String>,
+}
+
+impl Default for Hello {
+    fn default() -> Hello {
+        Hello {
+            protover: 3,
+            auth: None,
+            setname: None,
+        }
+    }
+}
+
+impl Hello {
+    pub fn new() -> Hello {
+        Hello::default()
+    }
+
+    pub fn protover(mut self, protover: i64) -> Hello {
+        self.protover = protover;
+        self
+    }
+
+    pub fn auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Hello {
+        self.auth = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn setname(mut self, name: impl Into<String>) -> Hello {
+        self.setname = Some(name.into());
+        self
+    }
+
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new("HELLO").arg(self.protover.to_string());
+
+        if let Some((user, pass)) = &self.auth {
+            cmd = cmd.arg("AUTH").arg(user.as_str()).arg(pass.as_str());
+        }
+
+        if let Some(name) = &self.setname {
+            cmd = cmd.arg("SETNAME").arg(name.as_str());
+        }
+
+        cmd
+    }
+}
+
+/// Server information returned by `HELLO`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub server: String,
+    pub version: String,
+    pub proto: i64,
+    pub id: i64,
+    pub mode: String,
+    pub role: String,
+    pub modules: Vec<Value>,
+}
+
+impl From<ServerInfo> for Value {
+    fn from(info: ServerInfo) -> Value {
+        map! {
+            "server" => info.server,
+            "version" => info.version,
+            "proto" => info.proto,
+            "id" => info.id,
+            "mode" => info.mode,
+            "role" => info.role,
+            "modules" => info.modules,
+        }
+    }
+}
+
+impl std::convert::TryFrom<Value> for ServerInfo {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        // RESP2 servers reply with a flat array of key/value pairs
+        let map = match value {
+            Value::Map(m) => m,
+            Value::Array(a) if a.len() % 2 == 0 => {
+                let mut map = Map::new();
+                let mut iter = a.into_iter();
+                while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+                    map.insert(k, v);
+                }
+                map
+            }
+            x => return Err(Error::InvalidValue(x)),
+        };
+
+        let string = |key: &str| {
+            map.get(&key.into())
+                .and_then(|x| x.as_string())
+                .map(String::from)
+        };
+
+        let int = |key: &str| map.get(&key.into()).and_then(|x| x.as_int());
+
+        let server = match string("server") {
+            Some(x) => x,
+            None => return Err(Error::InvalidValue(Value::Map(map))),
+        };
+
+        let proto = match int("proto") {
+            Some(x) => x,
+            None => return Err(Error::InvalidValue(Value::Map(map))),
+        };
+
+        Ok(ServerInfo {
+            server,
+            version: string("version").unwrap_or_default(),
+            proto,
+            id: int("id").unwrap_or_default(),
+            mode: string("mode").unwrap_or_default(),
+            role: string("role").unwrap_or_default(),
+            modules: map
+                .get(&"modules".into())
+                .and_then(|x| x.as_array())
+                .map(|x| x.to_vec())
+                .unwrap_or_default(),
+        })
+    }
+}
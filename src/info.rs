@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Sections included when `INFO` is called without arguments or with `default`
+const DEFAULT_SECTIONS: &[&str] = &["server", "clients", "stats"];
+
+const BUILTIN_SECTIONS: &[&str] = &["server", "clients", "stats", "commandstats"];
+
+/// Sections returned by the `INFO` command, handlers can add their own using `Handler::info`
+///
+/// ```rust
+/// # use worm::Info;
+/// let mut info = Info::new();
+/// info.section("keyspace").field("keys", 10).field("expires", 0);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Info {
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Info {
+    pub fn new() -> Info {
+        Info::default()
+    }
+
+    /// Start a new section, fields are added to the last section
+    pub fn section(&mut self, name: impl Into<String>) -> &mut Info {
+        self.sections.push((name.into(), Vec::new()));
+        self
+    }
+
+    pub fn field(&mut self, key: impl Into<String>, value: impl std::fmt::Display) -> &mut Info {
+        if self.sections.is_empty() {
+            self.section("Default");
+        }
+
+        if let Some((_, fields)) = self.sections.last_mut() {
+            fields.push((key.into(), value.to_string()));
+        }
+        self
+    }
+
+    /// Name of every section
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Get a field from a section, section names are case-insensitive
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(section))
+            .flat_map(|(_, fields)| fields.iter())
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parse the output of the `INFO` command
+    pub fn parse(s: &str) -> Info {
+        let mut info = Info::new();
+        for line in s.lines() {
+            if let Some(name) = line.strip_prefix('#') {
+                info.section(name.trim());
+            } else if let Some(i) = line.find(':') {
+                info.field(&line[..i], &line[i + 1..]);
+            }
+        }
+        info
+    }
+
+    /// Format the sections selected by the arguments to `INFO`
+    ///
+    /// No arguments or `default` selects the built-in sections other than `commandstats`, along
+    /// with every handler section. `all` and `everything` select every section
+    pub(crate) fn render(&self, args: &[String]) -> String {
+        let all = args
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case("all") || x.eq_ignore_ascii_case("everything"));
+        let default = args.is_empty() || args.iter().any(|x| x.eq_ignore_ascii_case("default"));
+
+        let mut out = Vec::new();
+        for (name, fields) in &self.sections {
+            let lower = name.to_ascii_lowercase();
+            let builtin = BUILTIN_SECTIONS.contains(&lower.as_str());
+            let selected = all
+                || args.iter().any(|x| x.eq_ignore_ascii_case(name))
+                || (default && (!builtin || DEFAULT_SECTIONS.contains(&lower.as_str())));
+            if !selected {
+                continue;
+            }
+
+            let mut section = format!("# {}\r\n", name);
+            for (k, v) in fields {
+                section.push_str(&format!("{}:{}\r\n", k, v));
+            }
+            out.push(section);
+        }
+        out.join("\r\n")
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CommandStats {
+    calls: u64,
+    usec: u64,
+    failed: u64,
+}
+
+/// Server-wide counters reported by `INFO`
+pub(crate) struct Stats {
+    started: std::time::Instant,
+    pub(crate) connections_received: AtomicU64,
+    pub(crate) rejected_connections: AtomicU64,
+    pub(crate) commands_processed: AtomicU64,
+    // Bytes transferred by connections that have been closed
+    pub(crate) net_input_bytes: AtomicU64,
+    pub(crate) net_output_bytes: AtomicU64,
+    commands: std::sync::Mutex<BTreeMap<String, CommandStats>>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            started: std::time::Instant::now(),
+            connections_received: Default::default(),
+            rejected_connections: Default::default(),
+            commands_processed: Default::default(),
+            net_input_bytes: Default::default(),
+            net_output_bytes: Default::default(),
+            commands: Default::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn incr(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Record a call to a command, unknown commands are only counted in the total
+    pub(crate) fn record(&self, name: Option<&str>, t: std::time::Duration, failed: bool) {
+        Stats::incr(&self.commands_processed, 1);
+
+        let name = match name {
+            Some(x) => x,
+            None => return,
+        };
+
+        let mut commands = self.commands.lock().unwrap();
+        let stats = match commands.get_mut(name) {
+            Some(x) => x,
+            None => commands.entry(name.to_string()).or_default(),
+        };
+        stats.calls += 1;
+        stats.usec += t.as_micros() as u64;
+        stats.failed += failed as u64;
+    }
+
+    /// Add the `server`, `clients`, `stats` and `commandstats` sections
+    pub(crate) fn info(&self, info: &mut Info, clients: usize, net: (u64, u64)) {
+        let uptime = self.started.elapsed().as_secs();
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+
+        info.section("Server")
+            .field("worm_version", env!("CARGO_PKG_VERSION"))
+            .field("process_id", std::process::id())
+            .field("uptime_in_seconds", uptime)
+            .field("uptime_in_days", uptime / 86400);
+
+        info.section("Clients").field("connected_clients", clients);
+
+        info.section("Stats")
+            .field(
+                "total_connections_received",
+                get(&self.connections_received),
+            )
+            .field("total_commands_processed", get(&self.commands_processed))
+            .field("total_net_input_bytes", get(&self.net_input_bytes) + net.0)
+            .field(
+                "total_net_output_bytes",
+                get(&self.net_output_bytes) + net.1,
+            )
+            .field("rejected_connections", get(&self.rejected_connections));
+
+        info.section("Commandstats");
+        for (name, stats) in self.commands.lock().unwrap().iter() {
+            info.field(
+                format!("cmdstat_{}", name),
+                format!(
+                    "calls={},usec={},usec_per_call={:.2},failed_calls={}",
+                    stats.calls,
+                    stats.usec,
+                    stats.usec as f64 / stats.calls as f64,
+                    stats.failed
+                ),
+            );
+        }
+    }
+}
@@ -10,20 +10,56 @@ pub(crate) mod internal {
     pub use crate::*;
 }
 
+mod acl;
+mod auth;
 mod client;
 mod command;
+mod config;
+mod context;
 mod decoder;
 mod encoder;
 mod error;
+mod glob;
+mod hello;
+mod info;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod middleware;
+mod monitor;
+mod pubsub;
+mod ratelimit;
+mod response;
 mod server;
+mod slowlog;
+mod stream;
+#[cfg(feature = "tower")]
+mod tower;
+mod transaction;
 mod value;
 
+pub use acl::{hash_password, Acl, User};
+pub use auth::{Authenticator, Principal};
 pub use client::Client;
 pub use command::Command;
+pub use config::ServerConfig;
+pub use context::{ClientFilter, ConnectionInfo, ServerContext};
 pub use decoder::Decoder;
 pub use encoder::Encoder;
-pub use error::Error;
-pub use server::{Handle, Handler, Response, Server};
+pub use error::{Error, ErrorCode, ReplyError};
+pub use glob::glob_match;
+pub use hello::{Hello, ServerInfo};
+pub use info::Info;
+#[cfg(feature = "metrics")]
+pub use metrics::{CommandMetrics, Histogram, Metrics};
+pub use middleware::{Middleware, Next};
+pub use pubsub::{Broker, Message, Pushes, Subscription};
+pub use ratelimit::{Limit, RateLimits};
+pub use response::{HandlerResult, Response};
+pub use server::{Handle, Handler, Server, ServerBuilder};
+pub use stream::{Counted, Stream};
+#[cfg(feature = "tower")]
+pub use tower::{ClientService, HandlerService, ServiceHandler};
+pub use transaction::{Queued, Replies, Transaction};
 pub use value::{Float, Map, Set, Value};
 
 pub use worm_derive::Handler;
@@ -31,5 +67,8 @@ pub use worm_derive::Handler;
 pub use async_trait::async_trait;
 pub use tokio::net::ToSocketAddrs;
 
+#[cfg(test)]
+extern crate self as worm;
+
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,308 @@
+use crate::internal::*;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Upper bounds of the latency histogram buckets in seconds, the same as the Prometheus client
+// defaults with a few smaller buckets for fast commands
+const BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+// Requests to the `/metrics` listener larger than this are rejected
+const MAX_REQUEST: usize = 8192;
+
+// Time allowed to send a request to the `/metrics` listener
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Cumulative histogram, `buckets` contains the number of observations less than or equal to
+/// each upper bound
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: BUCKETS.iter().map(|x| (*x, 0)).collect(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, x: f64) {
+        for (le, n) in self.buckets.iter_mut() {
+            if x <= *le {
+                *n += 1;
+            }
+        }
+        self.sum += x;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandMetrics {
+    pub calls: u64,
+    pub failed: u64,
+    /// Execution time in seconds
+    pub latency: Histogram,
+}
+
+/// Snapshot of the metrics for a server or client, returned by `ServerContext::metrics` and
+/// `Client::metrics`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Commands by name, commands unknown to the server are counted as `unknown`
+    pub commands: BTreeMap<String, CommandMetrics>,
+    pub connections: u64,
+    pub connections_received: u64,
+    pub rejected_connections: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub auth_failures: u64,
+    pub protocol_errors: u64,
+}
+
+impl Metrics {
+    /// Format the metrics using the Prometheus text format, every name starts with `worm_`
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "commands_total", "counter", "Commands executed");
+        for (name, cmd) in &self.commands {
+            let _ = writeln!(
+                out,
+                "worm_commands_total{{command=\"{}\"}} {}",
+                escape(name),
+                cmd.calls
+            );
+        }
+
+        header(
+            &mut out,
+            "command_errors_total",
+            "counter",
+            "Commands that replied with an error",
+        );
+        for (name, cmd) in &self.commands {
+            let _ = writeln!(
+                out,
+                "worm_command_errors_total{{command=\"{}\"}} {}",
+                escape(name),
+                cmd.failed
+            );
+        }
+
+        header(
+            &mut out,
+            "command_duration_seconds",
+            "histogram",
+            "Command execution time",
+        );
+        for (name, cmd) in &self.commands {
+            let name = escape(name);
+            for (le, n) in &cmd.latency.buckets {
+                let _ = writeln!(
+                    out,
+                    "worm_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    name, le, n
+                );
+            }
+            let _ = writeln!(
+                out,
+                "worm_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                name, cmd.latency.count
+            );
+            let _ = writeln!(
+                out,
+                "worm_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                name, cmd.latency.sum
+            );
+            let _ = writeln!(
+                out,
+                "worm_command_duration_seconds_count{{command=\"{}\"}} {}",
+                name, cmd.latency.count
+            );
+        }
+
+        let values: &[(&str, &str, &str, u64)] = &[
+            (
+                "connected_clients",
+                "gauge",
+                "Open connections",
+                self.connections,
+            ),
+            (
+                "connections_received_total",
+                "counter",
+                "Connections accepted",
+                self.connections_received,
+            ),
+            (
+                "rejected_connections_total",
+                "counter",
+                "Connections rejected because of maxclients",
+                self.rejected_connections,
+            ),
+            (
+                "net_input_bytes_total",
+                "counter",
+                "Bytes read",
+                self.bytes_read,
+            ),
+            (
+                "net_output_bytes_total",
+                "counter",
+                "Bytes written",
+                self.bytes_written,
+            ),
+            (
+                "auth_failures_total",
+                "counter",
+                "Failed authentication attempts",
+                self.auth_failures,
+            ),
+            (
+                "protocol_errors_total",
+                "counter",
+                "Invalid values received",
+                self.protocol_errors,
+            ),
+        ];
+        for (name, kind, help, value) in values {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "worm_{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP worm_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE worm_{} {}", name, kind);
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counters shared by every connection to a server, or owned by a single client
+#[derive(Default)]
+pub(crate) struct Recorder {
+    commands: std::sync::Mutex<BTreeMap<String, CommandMetrics>>,
+    auth_failures: AtomicU64,
+    protocol_errors: AtomicU64,
+}
+
+impl Recorder {
+    pub(crate) fn command(&self, name: &str, t: std::time::Duration, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let cmd = match commands.get_mut(name) {
+            Some(x) => x,
+            None => commands.entry(name.to_string()).or_default(),
+        };
+        cmd.calls += 1;
+        cmd.failed += failed as u64;
+        cmd.latency.observe(t.as_secs_f64());
+    }
+
+    pub(crate) fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn protocol_error(&self) {
+        self.protocol_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record `e` if it was caused by an invalid value
+    pub(crate) fn error(&self, e: &Error) {
+        if let Error::InvalidByte(_)
+        | Error::InvalidValue(_)
+        | Error::ParseInt(_)
+        | Error::ParseFloat(_) = e
+        {
+            self.protocol_error();
+        }
+    }
+
+    /// Snapshot of the command and error counters, connection counters are filled in by the caller
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            commands: self.commands.lock().unwrap().clone(),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            protocol_errors: self.protocol_errors.load(Ordering::Relaxed),
+            ..Metrics::default()
+        }
+    }
+}
+
+/// Serve `GET /metrics` until the listener fails
+pub(crate) async fn serve(listener: tokio::net::TcpListener, context: ServerContext) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("unable to accept metrics connection: {:?}", e);
+                tokio::time::sleep(stream::ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket, &context).await {
+                log::debug!("metrics request failed: ({}) {:?}", addr, e);
+            }
+        });
+    }
+}
+
+// Read a single request and close the connection after replying
+async fn respond(mut socket: tokio::net::TcpStream, context: &ServerContext) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, socket.read(&mut chunk))
+            .await
+            .map_err(|_| Error::Disconnect("read timed out".into()))??;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST {
+            return reply(&mut socket, "431 Request Header Fields Too Large", "").await;
+        }
+    }
+
+    let line = String::from_utf8_lossy(&buf);
+    let mut parts = line.lines().next().unwrap_or_default().split(' ');
+    match (parts.next(), parts.next().map(|x| x.split('?').next())) {
+        (Some("GET"), Some(Some("/metrics"))) => {
+            reply(&mut socket, "200 OK", &context.metrics().render()).await
+        }
+        (Some("GET"), _) => reply(&mut socket, "404 Not Found", "").await,
+        _ => reply(&mut socket, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn reply(socket: &mut tokio::net::TcpStream, status: &str, body: &str) -> Result<(), Error> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
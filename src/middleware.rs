@@ -0,0 +1,59 @@
+use crate::internal::*;
+
+/// Interceptor wrapping command execution, registered using `Server::middleware`
+///
+/// Middleware can inspect or rewrite a command before passing it to `Next::run`, change the
+/// response it returns, or reply without calling `next` at all. Middleware registered first runs
+/// first. Built-in commands also pass through the chain, except for commands executed as part of
+/// `EXEC` which are only seen as the `EXEC` command
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync + 'static {
+    async fn call(&self, client: &mut Client, command: Command, next: Next<'_>) -> HandlerResult;
+}
+
+// Executes a command once it has passed through all of the middleware
+pub(crate) trait Endpoint: Send {
+    fn execute<'a>(
+        &'a mut self,
+        client: &'a mut Client,
+        command: Command,
+    ) -> futures::future::BoxFuture<'a, HandlerResult>;
+}
+
+impl<T: Handler> Endpoint for T {
+    fn execute<'a>(
+        &'a mut self,
+        client: &'a mut Client,
+        command: Command,
+    ) -> futures::future::BoxFuture<'a, HandlerResult> {
+        Handler::execute(self, client, command)
+    }
+}
+
+/// The rest of the middleware chain, followed by the handler
+pub struct Next<'a> {
+    middleware: &'a [std::sync::Arc<dyn Middleware>],
+    endpoint: &'a mut dyn Endpoint,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [std::sync::Arc<dyn Middleware>],
+        endpoint: &'a mut dyn Endpoint,
+    ) -> Next<'a> {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    pub async fn run(self, client: &mut Client, command: Command) -> HandlerResult {
+        match self.middleware.split_first() {
+            Some((m, rest)) => {
+                m.call(client, command, Next::new(rest, self.endpoint))
+                    .await
+            }
+            None => self.endpoint.execute(client, command).await,
+        }
+    }
+}
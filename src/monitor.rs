@@ -0,0 +1,155 @@
+use crate::internal::*;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Number of lines buffered for each monitor, lines are dropped once it's full
+const BUFFER: usize = 1024;
+
+pub(crate) type Receiver = tokio::sync::mpsc::Receiver<String>;
+
+/// Connections that have sent `MONITOR`
+#[derive(Default)]
+pub(crate) struct Monitors {
+    senders: std::sync::Mutex<BTreeMap<u64, tokio::sync::mpsc::Sender<String>>>,
+    count: AtomicUsize,
+}
+
+impl Monitors {
+    pub(crate) fn add(&self, id: u64) -> Receiver {
+        let (tx, rx) = tokio::sync::mpsc::channel(BUFFER);
+        let mut senders = self.senders.lock().unwrap();
+        senders.insert(id, tx);
+        self.count.store(senders.len(), Ordering::Relaxed);
+        rx
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.remove(&id);
+        self.count.store(senders.len(), Ordering::Relaxed);
+    }
+
+    /// Send a command to every monitor, a monitor that isn't keeping up misses the command
+    /// instead of slowing down the connection that sent it
+    pub(crate) fn feed(&self, client: &Client, command: &Command) {
+        if self.count.load(Ordering::Relaxed) == 0 || command.name() == "monitor" {
+            return;
+        }
+
+        let line = format_line(client, command);
+        let senders = self.senders.lock().unwrap();
+        for (id, tx) in senders.iter() {
+            if let Err(tokio::sync::mpsc::error::TrySendError::Full(_)) = tx.try_send(line.clone())
+            {
+                log::debug!("monitor buffer full, dropping command: {}", id);
+            }
+        }
+    }
+}
+
+// Wait for the next line for a monitoring connection, never completes if it isn't monitoring
+pub(crate) async fn next(rx: &mut Option<Receiver>) -> Option<String> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+// `<timestamp> [<db> <addr>] "<name>" "<arg>" ...`
+fn format_line(client: &Client, command: &Command) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!(
+        "{}.{:06} [{} {}] {}",
+        now.as_secs(),
+        now.subsec_micros(),
+        client.db(),
+        client.addrs()[0],
+        quote(command.name().as_bytes())
+    );
+
+    let args = command.args();
+    let redacted = redacted(command);
+    for (i, arg) in args.iter().enumerate() {
+        line.push(' ');
+        if redacted.contains(&i) {
+            line.push_str("\"(redacted)\"");
+            continue;
+        }
+
+        match arg {
+            Value::String(s) => line.push_str(&quote(s.as_bytes())),
+            Value::Bytes(b) => line.push_str(&quote(b)),
+            Value::Int(i) => line.push_str(&quote(i.to_string().as_bytes())),
+            x => line.push_str(&quote(format!("{:?}", x).as_bytes())),
+        }
+    }
+    line
+}
+
+// Copy of `command` with credentials replaced, used for logging
+pub(crate) fn redact(command: &Command) -> Command {
+    let redacted = redacted(command);
+    let args = command
+        .args()
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            if redacted.contains(&i) {
+                Value::from("(redacted)")
+            } else {
+                x.clone()
+            }
+        })
+        .collect::<Vec<_>>();
+    Command::new(command.name()).with_args(args)
+}
+
+// Indices of arguments containing credentials
+pub(crate) fn redacted(command: &Command) -> Vec<usize> {
+    let args = command.args();
+    let is = |i: usize, s: &str| {
+        args.get(i)
+            .and_then(|x| x.as_string())
+            .map(|x| x.eq_ignore_ascii_case(s))
+            .unwrap_or(false)
+    };
+
+    match command.name() {
+        "auth" => (0..args.len()).collect(),
+        "hello" => match (1..args.len()).find(|i| is(*i, "auth")) {
+            Some(i) => vec![i + 1, i + 2],
+            None => vec![],
+        },
+        "acl" if is(0, "setuser") => (2..args.len())
+            .filter(|i| {
+                args[*i]
+                    .as_string()
+                    .map(|x| x.starts_with(['>', '<', '#', '!']))
+                    .unwrap_or(true)
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// Quote an argument, escaping anything that isn't printable so the line never contains a newline
+fn quote(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for &c in s {
+        match c {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            c if c.is_ascii_graphic() || c == b' ' => out.push(c as char),
+            c => out.push_str(&format!("\\x{:02x}", c)),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,575 @@
+use crate::internal::*;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// Sending half of the pushes queued for a server connection
+#[derive(Clone)]
+pub(crate) struct Sender {
+    tx: tokio::sync::mpsc::Sender<Value>,
+    overflow: std::sync::Arc<tokio::sync::Notify>,
+}
+
+/// Pushes queued for a server connection, see `channel`
+pub(crate) struct Receiver {
+    rx: tokio::sync::mpsc::Receiver<Value>,
+    overflow: std::sync::Arc<tokio::sync::Notify>,
+}
+
+/// Queue for the pushes sent to a connection, holding at most `limit` pushes. A connection that
+/// falls further behind is disconnected, like Redis' `client-output-buffer-limit`
+pub(crate) fn channel(limit: usize) -> (Sender, Receiver) {
+    let (tx, rx) = tokio::sync::mpsc::channel(limit.max(1));
+    let overflow = std::sync::Arc::new(tokio::sync::Notify::new());
+    (
+        Sender {
+            tx,
+            overflow: overflow.clone(),
+        },
+        Receiver { rx, overflow },
+    )
+}
+
+impl Sender {
+    /// Queue a push without waiting, returns `false` if the connection is closed or its queue is
+    /// full. A full queue disconnects the connection
+    pub(crate) fn send(&self, msg: Value) -> bool {
+        match self.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                self.overflow.notify_one();
+                false
+            }
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+impl Receiver {
+    /// Wait for the next push, returns `None` once a push has been dropped because the queue was
+    /// full
+    pub(crate) async fn recv(&mut self) -> Option<Value> {
+        use std::future::Future;
+
+        let overflow = self.overflow.notified();
+        let msg = self.rx.recv();
+        tokio::pin!(overflow, msg);
+
+        // Disconnect as soon as possible instead of writing the pushes already queued
+        futures::future::poll_fn(|cx| {
+            if overflow.as_mut().poll(cx).is_ready() {
+                return std::task::Poll::Ready(None);
+            }
+            msg.as_mut().poll(cx)
+        })
+        .await
+    }
+}
+
+// Number of pushes a client buffers until they're read using `Pushes`, and of messages buffered
+// for each `Subscription`, newer values are dropped once it's full
+pub(crate) const PUSH_BUFFER: usize = 1024;
+
+// Subscription confirmations are replies to the command that changed the subscriptions
+const CONFIRMATIONS: &[&str] = &["subscribe", "unsubscribe", "psubscribe", "punsubscribe"];
+
+#[derive(Default)]
+struct Subscriptions {
+    channels: BTreeMap<String, BTreeMap<u64, Sender>>,
+    patterns: BTreeMap<String, BTreeMap<u64, Sender>>,
+}
+
+/// Server-wide registry of channel and pattern subscriptions
+#[derive(Default)]
+pub struct Broker {
+    subs: std::sync::Mutex<Subscriptions>,
+}
+
+fn remove(map: &mut BTreeMap<String, BTreeMap<u64, Sender>>, key: &str, id: u64) {
+    if let Some(subs) = map.get_mut(key) {
+        subs.remove(&id);
+        if subs.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+impl Broker {
+    pub(crate) fn subscribe(&self, id: u64, sender: &Sender, channel: &str) {
+        let mut subs = self.subs.lock().unwrap();
+        subs.channels
+            .entry(channel.into())
+            .or_default()
+            .insert(id, sender.clone());
+    }
+
+    pub(crate) fn unsubscribe(&self, id: u64, channel: &str) {
+        let mut subs = self.subs.lock().unwrap();
+        remove(&mut subs.channels, channel, id);
+    }
+
+    pub(crate) fn psubscribe(&self, id: u64, sender: &Sender, pattern: &str) {
+        let mut subs = self.subs.lock().unwrap();
+        subs.patterns
+            .entry(pattern.into())
+            .or_default()
+            .insert(id, sender.clone());
+    }
+
+    pub(crate) fn punsubscribe(&self, id: u64, pattern: &str) {
+        let mut subs = self.subs.lock().unwrap();
+        remove(&mut subs.patterns, pattern, id);
+    }
+
+    /// Send `message` to every connection subscribed to `channel`, returns the number of receivers
+    ///
+    /// Messages are queued on each connection, so this never waits on slow subscribers
+    pub fn publish(&self, channel: &str, message: impl Into<Value>) -> usize {
+        let message = message.into();
+        let subs = self.subs.lock().unwrap();
+        let mut count = 0;
+
+        if let Some(subs) = subs.channels.get(channel) {
+            let msg = Value::Push("message".into(), vec![channel.into(), message.clone()]);
+            for tx in subs.values() {
+                if tx.send(msg.clone()) {
+                    count += 1;
+                }
+            }
+        }
+
+        for (pattern, subs) in subs.patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+
+            let msg = Value::Push(
+                "pmessage".into(),
+                vec![pattern.as_str().into(), channel.into(), message.clone()],
+            );
+            for tx in subs.values() {
+                if tx.send(msg.clone()) {
+                    count += 1;
+                }
+            }
+        }
+
+        count
+    }
+
+    /// Active channels, optionally filtered by a glob pattern
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let subs = self.subs.lock().unwrap();
+        subs.channels
+            .keys()
+            .filter(|x| pattern.map(|p| glob_match(p, x)).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    /// Number of subscribers to `channel`, not counting pattern subscribers
+    pub fn numsub(&self, channel: &str) -> usize {
+        let subs = self.subs.lock().unwrap();
+        subs.channels.get(channel).map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// Number of unique patterns subscribed to
+    pub fn numpat(&self) -> usize {
+        let subs = self.subs.lock().unwrap();
+        subs.patterns.len()
+    }
+
+    /// Remove all subscriptions for a connection
+    pub(crate) fn remove_client(&self, id: u64) {
+        let mut subs = self.subs.lock().unwrap();
+        let subs = &mut *subs;
+        for map in [&mut subs.channels, &mut subs.patterns].iter_mut() {
+            map.retain(|_, x| {
+                x.remove(&id);
+                !x.is_empty()
+            });
+        }
+    }
+}
+
+pub(crate) const COMMANDS: &[&str] = &[
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "publish",
+    "pubsub",
+];
+
+// Commands allowed while a RESP2 connection is subscribed
+pub(crate) const SUBSCRIBED_COMMANDS: &[&str] = &[
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "ping",
+    "quit",
+    "reset",
+];
+
+fn names(args: &[Value]) -> Result<Vec<String>, Value> {
+    args.iter()
+        .map(|x| match x {
+            Value::Bytes(b) => Ok(String::from_utf8_lossy(b).into_owned()),
+            x => String::try_from(x.clone())
+                .map_err(|_| Value::from(ReplyError::err("invalid channel name"))),
+        })
+        .collect()
+}
+
+// Subscription changes are confirmed with one push per channel, including the
+// number of channels and patterns the connection is still subscribed to
+fn confirm(client: &Client, kind: &str, name: Value) -> Value {
+    let count = client.subscription_count() as i64;
+    Value::Push(kind.into(), vec![name, count.into()])
+}
+
+pub(crate) async fn handle(client: &mut Client, command: Command) -> HandlerResult {
+    let (ctx, tx) = match (client.context.clone(), client.push.clone()) {
+        (Some(ctx), Some(tx)) => (ctx, tx),
+        _ => return Ok(ReplyError::err("pub/sub is not available").into()),
+    };
+
+    let broker = ctx.broker();
+
+    let (name, args) = command.split();
+    let channels = match names(&args) {
+        Ok(x) => x,
+        Err(e) => return Ok(e.into()),
+    };
+
+    let mut confirmations = Vec::new();
+
+    match name.as_str() {
+        "subscribe" | "psubscribe" if channels.is_empty() => Error::invalid_args(name, 0, 1),
+        "subscribe" => {
+            for channel in channels {
+                broker.subscribe(client.id, &tx, &channel);
+                client.subscriptions.insert(channel.clone());
+                confirmations.push(confirm(client, "subscribe", channel.into()));
+            }
+            Ok(Response::Values(confirmations))
+        }
+        "psubscribe" => {
+            for pattern in channels {
+                broker.psubscribe(client.id, &tx, &pattern);
+                client.patterns.insert(pattern.clone());
+                confirmations.push(confirm(client, "psubscribe", pattern.into()));
+            }
+            Ok(Response::Values(confirmations))
+        }
+        "unsubscribe" => {
+            let channels = if channels.is_empty() {
+                client.subscriptions.iter().cloned().collect()
+            } else {
+                channels
+            };
+
+            if channels.is_empty() {
+                confirmations.push(confirm(client, "unsubscribe", Value::Null));
+            }
+
+            for channel in channels {
+                broker.unsubscribe(client.id, &channel);
+                client.subscriptions.remove(&channel);
+                confirmations.push(confirm(client, "unsubscribe", channel.into()));
+            }
+            Ok(Response::Values(confirmations))
+        }
+        "punsubscribe" => {
+            let patterns = if channels.is_empty() {
+                client.patterns.iter().cloned().collect()
+            } else {
+                channels
+            };
+
+            if patterns.is_empty() {
+                confirmations.push(confirm(client, "punsubscribe", Value::Null));
+            }
+
+            for pattern in patterns {
+                broker.punsubscribe(client.id, &pattern);
+                client.patterns.remove(&pattern);
+                confirmations.push(confirm(client, "punsubscribe", pattern.into()));
+            }
+            Ok(Response::Values(confirmations))
+        }
+        "publish" => {
+            if args.len() != 2 {
+                return Error::invalid_args(name, args.len(), 2);
+            }
+            let n = broker.publish(&channels[0], args[1].clone());
+            Ok(Value::Int(n as i64).into())
+        }
+        "pubsub" => {
+            let subcommand = channels.first().map(|x| x.to_ascii_lowercase());
+            match subcommand.as_deref() {
+                Some("channels") => Ok(Value::Array(
+                    broker
+                        .channels(channels.get(1).map(|x| x.as_str()))
+                        .into_iter()
+                        .map(Value::from)
+                        .collect(),
+                )
+                .into()),
+                Some("numsub") => {
+                    let mut res = Vec::new();
+                    for channel in &channels[1..] {
+                        res.push(channel.as_str().into());
+                        res.push((broker.numsub(channel) as i64).into());
+                    }
+                    Ok(Value::Array(res).into())
+                }
+                Some("numpat") => Ok(Value::Int(broker.numpat() as i64).into()),
+                _ => Ok(
+                    ReplyError::err("unknown subcommand, expected CHANNELS, NUMSUB or NUMPAT")
+                        .into(),
+                ),
+            }
+        }
+        _ => Ok(Response::error("NOCOMMAND invalid command")),
+    }
+}
+
+/// Message received on a subscribed channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    pub pattern: Option<String>,
+    pub payload: Value,
+}
+
+impl Message {
+    fn into_push(self) -> Value {
+        let mut args = Vec::with_capacity(3);
+        let kind = match self.pattern {
+            Some(pattern) => {
+                args.push(pattern.into());
+                "pmessage"
+            }
+            None => "message",
+        };
+        args.push(self.channel.into());
+        args.push(self.payload);
+        Value::Push(kind.into(), args)
+    }
+
+    fn from_push(kind: &str, args: Vec<Value>) -> Option<Message> {
+        let mut args = args.into_iter();
+        let pattern = if kind == "pmessage" {
+            Some(String::try_from(args.next()?).ok()?)
+        } else {
+            None
+        };
+        let channel = String::try_from(args.next()?).ok()?;
+        let payload = args.next()?;
+        Some(Message {
+            channel,
+            pattern,
+            payload,
+        })
+    }
+}
+
+// Channels and patterns that are delivered to a single `Subscription`
+pub(crate) struct Route {
+    pub(crate) channels: std::collections::BTreeSet<String>,
+    pub(crate) patterns: std::collections::BTreeSet<String>,
+    tx: tokio::sync::mpsc::Sender<Message>,
+}
+
+pub(crate) type Routes = std::sync::Arc<std::sync::Mutex<Vec<Route>>>;
+
+impl Route {
+    pub(crate) fn new(
+        pattern: bool,
+        names: &[String],
+        tx: tokio::sync::mpsc::Sender<Message>,
+    ) -> Route {
+        let names = names.iter().cloned().collect();
+        let (channels, patterns) = if pattern {
+            (Default::default(), names)
+        } else {
+            (names, Default::default())
+        };
+        Route {
+            channels,
+            patterns,
+            tx,
+        }
+    }
+}
+
+/// Stream of messages for the channels or patterns passed to `Client::subscribe` or `Client::psubscribe`
+///
+/// Up to 1024 messages are buffered until they're read, newer messages are dropped while the
+/// buffer is full
+pub struct Subscription {
+    pub(crate) rx: tokio::sync::mpsc::Receiver<Message>,
+}
+
+impl futures::Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Message>> {
+        futures::Stream::poll_next(std::pin::Pin::new(&mut self.rx), cx)
+    }
+}
+
+/// Stream of pushes that aren't pub/sub messages for a `Subscription`, for example those sent
+/// using `ServerContext::send` or `ServerContext::broadcast`, returned by `Client::pushes`
+pub struct Pushes {
+    pub(crate) rx: tokio::sync::mpsc::Receiver<Value>,
+}
+
+impl futures::Stream for Pushes {
+    type Item = Value;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Value>> {
+        futures::Stream::poll_next(std::pin::Pin::new(&mut self.rx), cx)
+    }
+}
+
+// Send a message to its subscriptions, returns the message if none of them want it
+fn deliver(routes: &Routes, msg: Message) -> Option<Message> {
+    let mut routes = routes.lock().unwrap();
+    let mut delivered = false;
+    routes.retain(|route| {
+        let matched = match &msg.pattern {
+            Some(p) => route.patterns.contains(p),
+            None => route.channels.contains(&msg.channel),
+        };
+
+        // Routes are dropped once their `Subscription` has been dropped
+        let keep = !matched
+            || match route.tx.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                    log::debug!("subscription buffer full, dropping message");
+                    true
+                }
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
+            };
+        delivered |= matched && keep;
+        keep
+    });
+
+    if delivered {
+        None
+    } else {
+        Some(msg)
+    }
+}
+
+fn queue_push(pushes: &tokio::sync::mpsc::Sender<Value>, push: Value) {
+    if let Err(tokio::sync::mpsc::error::TrySendError::Full(_)) = pushes.try_send(push) {
+        log::debug!("push buffer full, dropping push");
+    }
+}
+
+// Send a value read by a client to where it belongs: messages go to their subscriptions and other
+// pushes to the client's push stream, replies (including subscription confirmations) are returned
+pub(crate) fn sort(
+    value: Value,
+    routes: &Routes,
+    pushes: &tokio::sync::mpsc::Sender<Value>,
+) -> Option<Value> {
+    match value {
+        Value::Push(kind, args) if kind == "message" || kind == "pmessage" => {
+            match Message::from_push(&kind, args) {
+                Some(msg) => {
+                    if let Some(msg) = deliver(routes, msg) {
+                        queue_push(pushes, msg.into_push());
+                    }
+                }
+                None => log::debug!("invalid {} push", kind),
+            }
+            None
+        }
+        Value::Push(kind, args) if CONFIRMATIONS.contains(&kind.as_str()) => {
+            Some(Value::Push(kind, args))
+        }
+        Value::Push(kind, args) => {
+            queue_push(pushes, Value::Push(kind, args));
+            None
+        }
+        value => Some(value),
+    }
+}
+
+// RESP2 has no push type, so pub/sub replies are sent as arrays. Messages are only converted while
+// the connection has subscriptions, confirmations are recognized by the subscription count they
+// end with, so replies to other commands that look like them are kept as arrays
+fn normalize_resp2(value: Value, subscribed: bool) -> Value {
+    let a = match value {
+        Value::Array(a) => a,
+        x => return x,
+    };
+
+    let kind = a
+        .first()
+        .and_then(|x| x.as_string())
+        .map(|x| x.to_ascii_lowercase());
+    let push = match kind.as_deref() {
+        Some("message") => subscribed && a.len() == 3,
+        Some("pmessage") => subscribed && a.len() == 4,
+        Some(k) if CONFIRMATIONS.contains(&k) => a.len() == 3 && matches!(a[2], Value::Int(_)),
+        _ => false,
+    };
+
+    match kind {
+        Some(kind) if push => Value::Push(kind, a.into_iter().skip(1).collect()),
+        _ => Value::Array(a),
+    }
+}
+
+/// Read values from `input` until it is closed, forwarding pub/sub messages to their
+/// subscriptions, other pushes to `pushes` and replies to `replies`
+pub(crate) async fn route<T: AsyncRead + Unpin + Send>(
+    mut input: Decoder<T>,
+    protocol: i64,
+    routes: Routes,
+    pushes: tokio::sync::mpsc::Sender<Value>,
+    replies: tokio::sync::mpsc::UnboundedSender<Result<Value, Error>>,
+) {
+    // Whether the last confirmation left the connection with any subscriptions
+    let mut subscribed = false;
+    loop {
+        let value = match input.decode().await {
+            Ok(x) if protocol < 3 => {
+                let x = normalize_resp2(x, subscribed);
+                match &x {
+                    Value::Push(kind, args) if CONFIRMATIONS.contains(&kind.as_str()) => {
+                        if let Some(Value::Int(n)) = args.get(1) {
+                            subscribed = *n > 0;
+                        }
+                    }
+                    _ => (),
+                }
+                x
+            }
+            Ok(x) => x,
+            Err(e) => {
+                let _ = replies.send(Err(e));
+                break;
+            }
+        };
+
+        if let Some(value) = sort(value, &routes, &pushes) {
+            if replies.send(Ok(value)).is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use crate::internal::*;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Buckets use the runtime's clock, so they follow `tokio::time::pause` like the delays do
+use tokio::time::Instant;
+
+pub(crate) const COMMANDS: &[&str] = &["ratelimit"];
+
+/// Token bucket allowing `rate` commands per second on average, with bursts of up to `burst`
+/// commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl Limit {
+    pub fn new(rate: f64, burst: u32) -> Limit {
+        Limit {
+            rate,
+            burst: burst.max(1),
+        }
+    }
+
+    /// Parse `rate` and `burst` arguments, as used by the config file
+    pub(crate) fn parse(rate: &str, burst: &str) -> Result<Limit, String> {
+        let rate = match rate.parse::<f64>() {
+            Ok(x) if x > 0.0 && x.is_finite() => x,
+            _ => return Err(format!("invalid rate '{}'", rate)),
+        };
+        let burst = burst
+            .parse::<u32>()
+            .map_err(|_| format!("invalid burst '{}'", burst))?;
+        Ok(Limit::new(rate, burst))
+    }
+}
+
+/// Command rate limits, every command has to be allowed by the limit for its connection, its
+/// user and each of its ACL categories
+///
+/// Connection limits apply to each connection separately, user limits are shared by all of the
+/// connections authenticated as the same user and category limits are shared by the whole server
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    /// Limit for each connection
+    pub client: Option<Limit>,
+
+    /// Limits by username, `*` applies to users without their own limit
+    pub users: BTreeMap<String, Limit>,
+
+    /// Limits by ACL category
+    pub categories: BTreeMap<String, Limit>,
+
+    /// Wait for the command to be allowed instead of replying with an error, this stops reading
+    /// from the connection until then
+    pub delay: bool,
+
+    /// Reply sent when a command is rejected
+    pub error: ReplyError,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            client: None,
+            users: BTreeMap::new(),
+            categories: BTreeMap::new(),
+            delay: false,
+            error: ReplyError::err("rate limit exceeded"),
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn new() -> RateLimits {
+        RateLimits::default()
+    }
+
+    pub fn client(mut self, limit: Limit) -> RateLimits {
+        self.client = Some(limit);
+        self
+    }
+
+    /// Set the limit for a user, `*` applies to users without their own limit
+    pub fn user(mut self, name: impl Into<String>, limit: Limit) -> RateLimits {
+        self.users.insert(name.into(), limit);
+        self
+    }
+
+    pub fn category(mut self, name: impl Into<String>, limit: Limit) -> RateLimits {
+        self.categories
+            .insert(name.into().to_ascii_lowercase(), limit);
+        self
+    }
+
+    pub fn delay(mut self, delay: bool) -> RateLimits {
+        self.delay = delay;
+        self
+    }
+
+    pub fn error(mut self, error: ReplyError) -> RateLimits {
+        self.error = error;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.client.is_none() && self.users.is_empty() && self.categories.is_empty()
+    }
+
+    fn user_limit(&self, name: &str) -> Option<Limit> {
+        self.users
+            .get(name)
+            .or_else(|| self.users.get("*"))
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Bucket {
+        Bucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.limit = limit;
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    // Time until tokens taken in advance have been paid back
+    fn wait(&self) -> Duration {
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.limit.rate)
+    }
+
+    fn to_value(&self) -> Value {
+        map! {
+            "rate" => self.limit.rate,
+            "burst" => self.limit.burst as i64,
+            "tokens" => self.tokens,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    limits: RateLimits,
+    clients: BTreeMap<u64, Bucket>,
+    users: BTreeMap<String, Bucket>,
+    categories: BTreeMap<String, Bucket>,
+}
+
+// Result of `RateLimiter::acquire`
+pub(crate) enum Decision {
+    Allow,
+    Wait(Duration),
+    Reject(ReplyError),
+}
+
+/// Limiter state shared by every connection, see `RateLimits`
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    buckets: std::sync::Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Replace the limits, existing buckets are kept and use the new limits from now on
+    pub(crate) fn configure(&self, limits: RateLimits) {
+        self.buckets.lock().unwrap().limits = limits;
+    }
+
+    /// Take a token for a command from every bucket that applies to it
+    ///
+    /// In delay mode the tokens are always taken and the command waits until they're paid back,
+    /// otherwise nothing is taken if the command isn't allowed right away
+    pub(crate) fn acquire(&self, id: u64, user: &str, categories: &[&str]) -> Decision {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            limits,
+            clients,
+            users,
+            categories: shared,
+        } = &mut *buckets;
+
+        for cat in categories {
+            if let Some(limit) = limits.categories.get(*cat) {
+                shared
+                    .entry(cat.to_string())
+                    .or_insert_with(|| Bucket::new(*limit, now));
+            }
+        }
+
+        let mut matched = Vec::new();
+        if let Some(limit) = limits.client {
+            matched.push((
+                limit,
+                clients.entry(id).or_insert_with(|| Bucket::new(limit, now)),
+            ));
+        }
+
+        if let Some(limit) = limits.user_limit(user) {
+            matched.push((
+                limit,
+                users
+                    .entry(user.to_string())
+                    .or_insert_with(|| Bucket::new(limit, now)),
+            ));
+        }
+
+        for (name, bucket) in shared.iter_mut() {
+            match limits.categories.get(name) {
+                Some(limit) if categories.contains(&name.as_str()) => {
+                    matched.push((*limit, bucket))
+                }
+                _ => (),
+            }
+        }
+
+        let mut matched: Vec<&mut Bucket> = matched
+            .into_iter()
+            .map(|(limit, bucket)| {
+                bucket.refill(limit, now);
+                bucket
+            })
+            .collect();
+
+        if limits.delay {
+            for bucket in matched.iter_mut() {
+                bucket.tokens -= 1.0;
+            }
+            return match matched.iter().map(|x| x.wait()).max() {
+                Some(t) if t > Duration::from_secs(0) => Decision::Wait(t),
+                _ => Decision::Allow,
+            };
+        }
+
+        if matched.iter().any(|x| x.tokens < 1.0) {
+            return Decision::Reject(limits.error.clone());
+        }
+
+        for bucket in matched.iter_mut() {
+            bucket.tokens -= 1.0;
+        }
+        Decision::Allow
+    }
+
+    pub(crate) fn remove_client(&self, id: u64) {
+        self.buckets.lock().unwrap().clients.remove(&id);
+    }
+
+    // Refill every bucket, keeping the limits
+    pub(crate) fn reset(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.clients.clear();
+        buckets.users.clear();
+        buckets.categories.clear();
+    }
+
+    /// Current state of every bucket, as returned by `RATELIMIT INFO`
+    pub(crate) fn info(&self) -> Value {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        fn section<K: ToString>(buckets: &mut BTreeMap<K, Bucket>, now: Instant) -> Value {
+            let mut map = Map::new();
+            for (k, bucket) in buckets.iter_mut() {
+                let limit = bucket.limit;
+                bucket.refill(limit, now);
+                map.insert(k.to_string().into(), bucket.to_value());
+            }
+            Value::Map(map)
+        }
+
+        map! {
+            "mode" => if buckets.limits.delay { "delay" } else { "error" },
+            "clients" => section(&mut buckets.clients, now),
+            "users" => section(&mut buckets.users, now),
+            "categories" => section(&mut buckets.categories, now),
+        }
+    }
+}
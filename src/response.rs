@@ -0,0 +1,163 @@
+use crate::internal::*;
+
+/// Result type returned by command handlers
+pub type HandlerResult = anyhow::Result<Response>;
+
+/// Reply returned by a command handler
+pub enum Response {
+    /// A single value
+    Value(Value),
+
+    /// Several top-level values written one after the other, used for replies like `SUBSCRIBE`
+    /// confirmations that send one message per argument
+    Values(Vec<Value>),
+
+    /// An array whose items are produced by a stream. The server collects the stream while
+    /// executing the command, so the command timeout and panic handling apply to it
+    Stream(futures::stream::BoxStream<'static, Value>),
+
+    /// Pre-encoded RESP data, written as-is regardless of the protocol version
+    Raw(Vec<u8>),
+
+    /// A reply produced by a future that doesn't use the handler, the server awaits it after
+    /// releasing the handler lock so other connections can execute commands in the meantime.
+    /// Inside `MULTI` it's awaited while holding the lock, like the rest of the transaction
+    Deferred(futures::future::BoxFuture<'static, HandlerResult>),
+}
+
+impl std::fmt::Debug for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Value(x) => f.debug_tuple("Value").field(x).finish(),
+            Response::Values(x) => f.debug_tuple("Values").field(x).finish(),
+            Response::Stream(_) => f.write_str("Stream(..)"),
+            Response::Deferred(_) => f.write_str("Deferred(..)"),
+            Response::Raw(x) => f
+                .debug_tuple("Raw")
+                .field(&String::from_utf8_lossy(x))
+                .finish(),
+        }
+    }
+}
+
+impl From<Value> for Response {
+    fn from(x: Value) -> Response {
+        Response::Value(x)
+    }
+}
+
+impl From<ReplyError> for Response {
+    fn from(x: ReplyError) -> Response {
+        Response::Value(x.into())
+    }
+}
+
+impl Response {
+    pub fn ok() -> Response {
+        Response::Value(Value::ok())
+    }
+
+    pub fn error(x: impl Into<String>) -> Response {
+        Response::Value(Value::error(x))
+    }
+
+    pub fn stream(x: impl futures::Stream<Item = Value> + Send + 'static) -> Response {
+        Response::Stream(Box::pin(x))
+    }
+
+    pub fn raw(x: impl Into<Vec<u8>>) -> Response {
+        Response::Raw(x.into())
+    }
+
+    pub fn deferred(
+        x: impl std::future::Future<Output = HandlerResult> + Send + 'static,
+    ) -> Response {
+        Response::Deferred(Box::pin(x))
+    }
+
+    // Whether the reply is produced by a stream or future that hasn't been polled yet
+    pub(crate) fn is_deferred(&self) -> bool {
+        matches!(self, Response::Stream(_) | Response::Deferred(_))
+    }
+
+    // Collect streams and await deferred replies
+    pub(crate) async fn resolve(self) -> HandlerResult {
+        use futures::StreamExt;
+
+        match self {
+            Response::Stream(x) => Ok(Response::Value(Value::Array(x.collect().await))),
+            Response::Deferred(x) => Box::pin(x.await?.resolve()).await,
+            x => Ok(x),
+        }
+    }
+
+    // Reply to a deferred future, errors are converted to error replies like the errors returned
+    // by handlers
+    async fn reply(x: futures::future::BoxFuture<'static, HandlerResult>) -> Response {
+        x.await
+            .unwrap_or_else(|e| Response::Value(server::error_reply(e)))
+    }
+
+    /// Convert the response into a single value, streams are collected into an array and raw
+    /// data is decoded
+    pub async fn into_value(self) -> Result<Value, Error> {
+        use futures::StreamExt;
+
+        match self {
+            Response::Value(x) => Ok(x),
+            Response::Values(x) => Ok(Value::Array(x)),
+            Response::Stream(x) => Ok(Value::Array(x.collect().await)),
+            Response::Raw(x) => Value::read(&mut x.as_slice()).await,
+            Response::Deferred(x) => Box::pin(Response::reply(x).await.into_value()).await,
+        }
+    }
+
+    pub async fn write<T: Unpin + Send + AsyncWrite>(
+        self,
+        output: &mut Encoder<T>,
+    ) -> Result<(), Error> {
+        use futures::StreamExt;
+
+        match self {
+            Response::Value(x) => output.encode(&x).await,
+            Response::Values(x) => {
+                for value in x {
+                    output.encode(&value).await?;
+                }
+                Ok(())
+            }
+            Response::Stream(mut x) => match x.size_hint() {
+                (lower, Some(upper)) if lower == upper => {
+                    output.write_array_header(upper).await?;
+                    let mut n = 0;
+                    while let Some(value) = x.next().await {
+                        if n == upper {
+                            log::error!("stream returned more items than expected");
+                            break;
+                        }
+                        output.encode(&value).await?;
+                        n += 1;
+                    }
+
+                    // Keep the connection in a valid state if the stream ended early
+                    if n < upper {
+                        log::error!("stream returned fewer items than expected");
+                    }
+                    for _ in n..upper {
+                        output.write_null().await?;
+                    }
+                    Ok(())
+                }
+                _ => {
+                    let values: Vec<Value> = x.collect().await;
+                    output.write_array(&values).await
+                }
+            },
+            Response::Raw(x) => {
+                output.output.write_all(&x).await?;
+                Ok(())
+            }
+            Response::Deferred(x) => Box::pin(Response::reply(x).await.write(output)).await,
+        }
+    }
+}
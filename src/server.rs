@@ -1,6 +1,5 @@
 use crate::internal::*;
 
-
 pub struct Handle<T: Sized>(std::sync::Arc<tokio::sync::Mutex<T>>);
 
 impl<T: Sized> Handle<T> {
@@ -12,228 +11,1647 @@ impl<T: Sized> Handle<T> {
 unsafe impl<T> Send for Handle<T> {}
 unsafe impl<T> Sync for Handle<T> {}
 
+type Hook = std::sync::Arc<dyn Fn(&ConnectionInfo) + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct Hooks {
+    connect: Vec<Hook>,
+    disconnect: Vec<Hook>,
+}
+
+// Settings shared by every connection to a running server
+struct Options {
+    config: ServerConfig,
+    middleware: Vec<std::sync::Arc<dyn Middleware>>,
+}
+
 pub struct Server<T> {
     data: T,
+    context: ServerContext,
+    config: ServerConfig,
+    hooks: Hooks,
+    middleware: Vec<std::sync::Arc<dyn Middleware>>,
+    shutdown: Option<futures::future::BoxFuture<'static, ()>>,
+    listeners: Vec<stream::Listener>,
+    #[cfg(feature = "metrics")]
+    metrics_listener: Option<tokio::net::TcpListener>,
+}
+
+/// Builder for a `Server`, created using `Server::builder`
+pub struct ServerBuilder<T> {
+    server: Server<T>,
+}
+
+impl<T: 'static + Handler + Send> ServerBuilder<T> {
+    /// Add an address to listen on, either `host:port` or `unix:/path/to/socket`
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.server.config.bind.push(addr.into());
+        self
+    }
+
+    /// Accept connections from a listener that is already bound, see `Server::listener`
+    pub fn listener(mut self, listener: tokio::net::TcpListener) -> Self {
+        self.server = self.server.listener(listener);
+        self
+    }
+
+    /// Replace the current config
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.server.config = config;
+        self
+    }
+
+    /// Replace the current config with one loaded from a file, see `ServerConfig::parse`
+    pub fn config_file(self, path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        Ok(self.config(ServerConfig::load(path)?))
+    }
+
+    /// Protocol version used by new connections until they send `HELLO`
+    pub fn protocol(mut self, protocol: i64) -> Self {
+        self.server.config.protocol = protocol;
+        self
+    }
+
+    pub fn max_clients(mut self, n: usize) -> Self {
+        self.server.config.max_clients = n;
+        self
+    }
+
+    /// Number of databases that can be selected using `SELECT`
+    pub fn databases(mut self, n: usize) -> Self {
+        self.server.config.databases = n;
+        self
+    }
+
+    pub fn max_batch(mut self, n: usize) -> Self {
+        self.server.config.max_batch = n.max(1);
+        self
+    }
+
+    /// Require a password for the `default` user
+    pub fn requirepass(mut self, password: impl Into<String>) -> Self {
+        self.server.config.requirepass = Some(password.into());
+        self
+    }
+
+    /// Add an ACL user, `rules` contains the username followed by `ACL SETUSER` rules
+    pub fn user(mut self, rules: impl Into<String>) -> Self {
+        self.server.config.users.push(rules.into());
+        self
+    }
+
+    pub fn log_level(mut self, level: log::LevelFilter) -> Self {
+        self.server.config.log_level = Some(level);
+        self
+    }
+
+    /// Limit the rate commands are executed at, see `RateLimits`
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.server.config.rate_limits = limits;
+        self
+    }
+
+    /// Serve metrics in the Prometheus text format at `http://<addr>/metrics`
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, addr: impl Into<String>) -> Self {
+        self.server.config.metrics_bind = Some(addr.into());
+        self
+    }
+
+    /// Serve metrics using a listener that is already bound, instead of binding the address set
+    /// using `metrics`
+    #[cfg(feature = "metrics")]
+    pub fn metrics_listener(mut self, listener: tokio::net::TcpListener) -> Self {
+        self.server.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Add commands that take at least `t` to the `SLOWLOG`, `None` disables the slow log
+    pub fn slowlog_threshold(mut self, t: Option<std::time::Duration>) -> Self {
+        self.server.config.slowlog_threshold = t;
+        self
+    }
+
+    /// Maximum number of `SLOWLOG` entries
+    pub fn slowlog_max_len(mut self, n: usize) -> Self {
+        self.server.config.slowlog_max_len = n;
+        self
+    }
+
+    /// Add middleware around command execution, see `Middleware`
+    pub fn middleware(mut self, m: impl Middleware) -> Self {
+        self.server.middleware.push(std::sync::Arc::new(m));
+        self
+    }
+
+    /// Called after a connection has been accepted
+    pub fn on_connect(mut self, f: impl Fn(&ConnectionInfo) + Send + Sync + 'static) -> Self {
+        self.server.hooks.connect.push(std::sync::Arc::new(f));
+        self
+    }
+
+    /// Called when a connection is closed
+    pub fn on_disconnect(mut self, f: impl Fn(&ConnectionInfo) + Send + Sync + 'static) -> Self {
+        self.server.hooks.disconnect.push(std::sync::Arc::new(f));
+        self
+    }
+
+    /// Stop accepting connections once `signal` completes
+    pub fn shutdown(
+        mut self,
+        signal: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        self.server.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Check the config and create the server
+    pub fn build(self) -> Result<Server<T>, Error> {
+        let config = &self.server.config;
+        check_config(config, self.server.listeners.len())?;
+
+        // Users are checked against an empty ACL, they're only added when the server starts
+        apply_users(config, &Acl::default())?;
+        Ok(self.server)
+    }
 }
 
-pub type Response = anyhow::Result<Value>;
+// Check the settings that can be changed after the config is parsed, `listeners` is the number of
+// listeners added using `Server::listener`
+fn check_config(config: &ServerConfig, listeners: usize) -> Result<(), Error> {
+    if config.bind.is_empty() && listeners == 0 {
+        return Err(Error::Config("no bind address".into()));
+    }
+
+    if config.protocol != 2 && config.protocol != 3 {
+        return Err(Error::Config(format!(
+            "unsupported protocol version {}",
+            config.protocol
+        )));
+    }
+
+    if config.databases == 0 {
+        return Err(Error::Config("databases must be greater than 0".into()));
+    }
+
+    Ok(())
+}
+
+// Add the users and password from the config to the ACL
+fn apply_users(config: &ServerConfig, acl: &Acl) -> Result<(), Error> {
+    if let Some(password) = &config.requirepass {
+        acl.set_user(
+            "default",
+            &["resetpass".to_string(), format!(">{}", password)],
+        )
+        .map_err(|e| Error::Config(e.message))?;
+    }
+
+    for user in &config.users {
+        let mut rules = user.split_whitespace();
+        let name = match rules.next() {
+            Some(x) => x,
+            None => return Err(Error::Config("empty user".into())),
+        };
+        acl.set_user(name, rules)
+            .map_err(|e| Error::Config(format!("user {}: {}", name, e.message)))?;
+    }
+
+    Ok(())
+}
 
 #[macro_export]
 macro_rules! commands {
     ($($x:ident: $n:expr),*$(,)?) => {
         fn commands(&self) -> &[&str] {
             &[$(
-                stringify!($x),
+                $n,
             )*]
         }
 
-        fn call(this: worm::Handle<Self>, client: std::pin::Pin<&mut $crate::Client>, command: $crate::Command) -> std::pin::Pin<Box<dyn '_ + Send + std::future::Future<Output = $crate::Response>>> {
-            use std::ops::DerefMut;
+        fn dispatch<'a>(&'a mut self, client: std::pin::Pin<&'a mut $crate::Client>, command: $crate::Command) -> std::pin::Pin<Box<dyn 'a + Send + std::future::Future<Output = $crate::HandlerResult>>> {
             Box::pin(async move {
                 match command.name() {
                     $(
-                        $n => Self::$x(this.lock().await.deref_mut(), client, command).await,
+                        $n => Self::$x(self, client, command).await,
                     )*
-                    _ => Ok($crate::Value::error("NOCOMMAND invalid command")),
+                    _ => Ok($crate::Response::error("NOCOMMAND invalid command")),
                 }
             })
         }
     }
 }
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const BUILTIN_COMMANDS: &[&str] = &[
+    "hello", "auth", "ping", "commands", "client", "reset", "info", "monitor", "select",
+];
 
 #[async_trait::async_trait]
 pub trait Handler: Send + Sized {
-    fn call(this: Handle<Self>, client: std::pin::Pin<&mut Client>, command: Command) -> std::pin::Pin<Box<dyn '_ + Send + std::future::Future<Output = Response>>>;
+    fn dispatch<'a>(
+        &'a mut self,
+        client: std::pin::Pin<&'a mut Client>,
+        command: Command,
+    ) -> std::pin::Pin<Box<dyn 'a + Send + std::future::Future<Output = HandlerResult>>>;
 
     fn commands(&self) -> &[&str];
 
     fn password_required(&self) -> bool;
     fn _check_password(&self, _username: &str, _password: &str) -> bool;
 
-    fn handle_hello(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
-        log::info!("hello: ({}) {:?}", client.addrs()[0], args);
-        if args.len() == 0 {
-            return Error::invalid_args("hello", 0, 1);
+    /// ACL categories of a command implemented by the handler, every command is part of `@all`
+    fn categories(&self, _command: &str) -> &[&str] {
+        &[]
+    }
+
+    /// Arguments of a command implemented by the handler that are keys, these are checked against
+    /// the key patterns of the connection's ACL user
+    fn keys<'a>(&self, _command: &'a Command) -> Vec<&'a Value> {
+        Vec::new()
+    }
+
+    /// Whether the handler implements `command`, by default only the commands listed by
+    /// `commands`. Handlers that accept any command name, like `ServiceHandler`, override this so
+    /// their commands can be queued by `MULTI` and are counted by name in `INFO` and metrics
+    fn has_command(&self, command: &str) -> bool {
+        self.commands().contains(&command)
+    }
+
+    /// Add application sections to the output of `INFO`, sections added here are included by
+    /// default and can also be requested by name
+    fn info(&self, _info: &mut Info) {}
+
+    /// Asynchronous password verification, called without holding the handler lock. When this
+    /// returns `Some` the password function is only used if the authenticator isn't available
+    fn authenticator(&self) -> Option<std::sync::Arc<dyn Authenticator>> {
+        None
+    }
+
+    /// Check a username and password using the result of the handler's authenticator, then its
+    /// password function, falling back to the ACL
+    fn authenticate(
+        &self,
+        client: &mut Client,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal, ReplyError> {
+        // Users from the password function or authenticator that aren't in the ACL are given the
+        // permissions of the default user
+        let res = self
+            .check_credentials(client, username, password)
+            .map(|principal| match client.context() {
+                Some(ctx) if ctx.acl().user(principal.user()).is_none() => {
+                    principal.with_acl_user("default")
+                }
+                _ => principal,
+            });
+
+        #[cfg(feature = "metrics")]
+        if let (Err(_), Some(ctx)) = (&res, client.context()) {
+            ctx.recorder().auth_failure();
         }
 
-        if args[0].as_int() != Some(3) {
-            return Error::disconnect(
-                "NOPROTO sorry this protocol version is not supported, only RESP3 is supported",
-            );
+        res
+    }
+
+    fn check_credentials(
+        &self,
+        client: &mut Client,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal, ReplyError> {
+        if let Some(res) = client.verified.take() {
+            return res;
         }
 
-        if args.len() >= 3 {
-            let auth = args[1].as_string().unwrap();
-            if auth != "auth" && auth != "AUTH" {
-                return Error::disconnect("ERR invalid hello command, expected AUTH argument");
-            }
+        let ok = (self.password_required() && self._check_password(username, password))
+            || match client.context() {
+                Some(ctx) => ctx
+                    .acl()
+                    .authenticate(username, password, !self.password_required()),
+                None => !self.password_required(),
+            };
 
-            if self.password_required() {
-                let username = if args.len() == 3 {
-                    "default"
-                } else {
-                    args[2].as_string().unwrap()
-                };
-                let password = args[if args.len() == 3 { 2 } else { 3 }]
-                    .as_string()
-                    .unwrap();
-                if !self._check_password(username, password) {
-                    return Error::disconnect("ERR invalid password");
+        if !ok {
+            return Err(ReplyError::err("invalid password"));
+        }
+
+        Ok(Principal::new(username))
+    }
+
+    /// Check the permissions of the connection's ACL user to execute `command`
+    fn check_permissions(&self, client: &Client, command: &Command) -> Result<(), ReplyError> {
+        let ctx = match client.context() {
+            Some(ctx) => ctx,
+            None => return Ok(()),
+        };
+
+        let name = command.name();
+        let args = command.args();
+        let categories = match acl::builtin_categories(command) {
+            Some(x) => x,
+            None => self.categories(name),
+        };
+
+        let (keys, channels) = match name {
+            "watch" => (args.iter().collect(), vec![]),
+            "subscribe" | "psubscribe" => (vec![], args.iter().collect()),
+            "publish" => (vec![], args.iter().take(1).collect()),
+            _ if categories.contains(&"pubsub") => (vec![], vec![]),
+            _ => (self.keys(command), vec![]),
+        };
+
+        ctx.acl().check(
+            client
+                .principal()
+                .map(|x| x.acl_user())
+                .unwrap_or("default"),
+            name,
+            categories,
+            &keys,
+            &channels,
+        )
+    }
+
+    fn handle_hello(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        log::info!("hello: ({})", client.addrs()[0]);
+
+        let protover = match args.first() {
+            None => client.output.protocol(),
+            Some(x) => match x.as_int() {
+                Some(n) if n == 2 || n == 3 => n,
+                _ => {
+                    return Ok(Value::reply_error(
+                        ErrorCode::NoProto,
+                        "sorry this protocol version is not supported",
+                    ))
+                }
+            },
+        };
+
+        let mut auth = None;
+        let mut setname = None;
+        let mut i = 1;
+        while i < args.len() {
+            let opt = args[i].as_string().map(|x| x.to_ascii_lowercase());
+            match opt.as_deref() {
+                // Unlike `AUTH`, the username is always required
+                Some("auth") if args.len() - i > 2 => {
+                    auth = Some((args[i + 1].as_string(), args[i + 2].as_string()));
+                    i += 3;
+                }
+                Some("auth") => {
+                    return Ok(Value::reply_error(
+                        ErrorCode::Err,
+                        "syntax error in HELLO option 'auth'",
+                    ))
+                }
+                Some("setname") if args.len() - i >= 2 => {
+                    setname = args[i + 1].as_string();
+                    if setname.is_none() {
+                        return Error::disconnect("ERR invalid client name");
+                    }
+                    i += 2;
+                }
+                _ => {
+                    return Error::disconnect(
+                        "ERR invalid hello command, expected AUTH or SETNAME argument",
+                    )
                 }
             }
+        }
+
+        if let Some(auth) = auth {
+            let (username, password) = match auth {
+                (Some(u), Some(p)) => (u, p),
+                _ => return Error::disconnect("ERR invalid password"),
+            };
+
+            let principal = match self.authenticate(client, username, password) {
+                Ok(x) => x,
+                // A connection that is already authenticated keeps its current identity
+                Err(e) if client.authenticated => return Ok(e.into()),
+                Err(e) => return Error::disconnect(e.to_string()),
+            };
 
             client.authenticated = true;
-        } else if self.password_required() && !client.authenticated {
+            client.principal = Some(principal);
+        } else if !client.authenticated {
             return Error::disconnect("ERR password required");
         }
 
-        return Ok(map! {
-            "server" => "worm",
-            "version" => VERSION,
-            "proto" => 3,
-        });
-    }
+        if let Some(name) = setname {
+            client.name = Some(name.into());
+        }
 
-    fn handle_auth(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
-        log::info!("auth: ({}) {:?}", client.addrs()[0], args);
+        client.output.set_protocol(protover);
 
-        if args.len() == 0 {
-            return Error::invalid_args("auth", 0, 1);
+        Ok(ServerInfo {
+            server: "worm".into(),
+            version: VERSION.into(),
+            proto: protover,
+            id: client.id() as i64,
+            mode: "standalone".into(),
+            role: "master".into(),
+            modules: vec![],
         }
+        .into())
+    }
 
-        let username = if args.len() == 1 {
-            "default"
-        } else {
-            args[1].as_string().unwrap()
+    fn handle_auth(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        log::info!("auth: ({})", client.addrs()[0]);
+
+        let (username, password) = match args {
+            [password] => (Some("default"), password.as_string()),
+            [username, password, ..] => (username.as_string(), password.as_string()),
+            [] => return Error::invalid_args("auth", 0, 1),
         };
 
-        let password = args[if args.len() == 1 { 0 } else { 1 }]
-            .as_string()
-            .unwrap();
+        let (username, password) = match (username, password) {
+            (Some(u), Some(p)) => (u, p),
+            _ => return Ok(ReplyError::err("invalid username or password argument").into()),
+        };
 
-        if !self._check_password(username, password) {
-            return Error::disconnect("ERR invalid password");
-        }
+        let principal = match self.authenticate(client, username, password) {
+            Ok(x) => x,
+            Err(e) if client.authenticated => return Ok(e.into()),
+            Err(e) => return Error::disconnect(e.to_string()),
+        };
 
         client.authenticated = true;
+        client.principal = Some(principal);
 
-        return Ok(Value::ok());
+        Ok(Value::ok())
     }
 
     fn handle_commands(&mut self, _client: &mut Client, _args: &[Value]) -> Result<Value, Error> {
         let mut cmds = self.commands().to_vec();
-        cmds.push("hello");
-        cmds.push("auth");
-        cmds.push("ping");
-        cmds.push("commands");
+        cmds.extend_from_slice(BUILTIN_COMMANDS);
+        cmds.extend_from_slice(pubsub::COMMANDS);
+        cmds.extend_from_slice(transaction::COMMANDS);
+        cmds.extend_from_slice(acl::COMMANDS);
+        cmds.extend_from_slice(ratelimit::COMMANDS);
+        cmds.extend_from_slice(slowlog::COMMANDS);
         Ok(Value::Array(cmds.into_iter().map(|x| x.into()).collect()))
     }
 
+    fn handle_client(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        let sub = args
+            .first()
+            .and_then(|x| x.as_string())
+            .map(|x| x.to_ascii_lowercase());
+        match (sub.as_deref(), args.len()) {
+            (Some("id"), 1) => Ok(Value::from(client.id() as i64)),
+            (Some("info"), 1) => Ok(Value::from(context::client_info(client))),
+            (Some("getname"), 1) => Ok(client.name().map(Value::from).unwrap_or(Value::Null)),
+            (Some("setname"), 2) => match args[1].as_string() {
+                Some(name) if !name.contains(' ') => {
+                    client.name = if name.is_empty() {
+                        None
+                    } else {
+                        Some(name.into())
+                    };
+                    Ok(Value::ok())
+                }
+                _ => Ok(ReplyError::err("Client names cannot contain spaces").into()),
+            },
+            (Some("list"), _) | (Some("kill"), _) | (Some("pause"), _) | (Some("unpause"), 1) => {
+                let ctx = match client.context.clone() {
+                    Some(ctx) => ctx,
+                    None => return Ok(ReplyError::err("connection registry is not available").into()),
+                };
+                self.handle_client_admin(client, &ctx, args)
+            }
+            _ => Ok(ReplyError::err(
+                "unknown subcommand or wrong number of arguments, expected ID, INFO, LIST, GETNAME, SETNAME, KILL, PAUSE or UNPAUSE",
+            )
+            .into()),
+        }
+    }
+
+    // `CLIENT` subcommands that affect other connections
+    fn handle_client_admin(
+        &mut self,
+        client: &mut Client,
+        ctx: &ServerContext,
+        args: &[Value],
+    ) -> anyhow::Result<Value> {
+        let sub = args[0].as_string().unwrap_or_default().to_ascii_lowercase();
+        let rest = &args[1..];
+        let syntax = || Ok(ReplyError::err("syntax error").into());
+
+        match sub.as_str() {
+            "list" => {
+                let ids = match rest {
+                    [] => Vec::new(),
+                    [kind, ids @ ..]
+                        if kind.as_string().map(|x| x.eq_ignore_ascii_case("id")) == Some(true) =>
+                    {
+                        match ids
+                            .iter()
+                            .map(|x| x.as_int().filter(|x| *x > 0))
+                            .collect::<Option<Vec<_>>>()
+                        {
+                            Some(ids) if !ids.is_empty() => {
+                                ids.into_iter().map(|x| x as u64).collect()
+                            }
+                            _ => return Ok(ReplyError::err("Invalid client ID").into()),
+                        }
+                    }
+                    _ => return syntax(),
+                };
+                Ok(Value::String(ctx.client_list(&ids)))
+            }
+            "kill" => {
+                // The old form takes a single address and replies with OK
+                if let [addr] = rest {
+                    let addr = addr.as_string().and_then(|x| x.parse().ok());
+                    let filter = ClientFilter {
+                        addr,
+                        ..Default::default()
+                    };
+                    return match addr {
+                        Some(_) if ctx.kill(&filter) > 0 => Ok(Value::ok()),
+                        _ => Ok(ReplyError::err("No such client").into()),
+                    };
+                }
+
+                let mut filter = ClientFilter {
+                    skip: Some(client.id()),
+                    ..Default::default()
+                };
+                if rest.is_empty() {
+                    return syntax();
+                }
+                for pair in rest.chunks(2) {
+                    let (name, arg) = match pair {
+                        [name, arg] => (name.as_string().map(|x| x.to_ascii_lowercase()), arg),
+                        _ => return syntax(),
+                    };
+                    let value = arg.as_string().unwrap_or_default();
+                    match name.as_deref().unwrap_or_default() {
+                        "id" => match arg.as_int().filter(|x| *x > 0) {
+                            Some(id) => filter.id = Some(id as u64),
+                            None => return Ok(ReplyError::err("Invalid client ID").into()),
+                        },
+                        "addr" => match value.parse() {
+                            Ok(addr) => filter.addr = Some(addr),
+                            Err(_) => return Ok(ReplyError::err("Invalid address").into()),
+                        },
+                        "user" => filter.user = Some(value.to_string()),
+                        "skipme" => {
+                            filter.skip = match value.to_ascii_lowercase().as_str() {
+                                "yes" => Some(client.id()),
+                                "no" => None,
+                                _ => return syntax(),
+                            }
+                        }
+                        _ => return syntax(),
+                    }
+                }
+                Ok(Value::from(ctx.kill(&filter) as i64))
+            }
+            "pause" => {
+                let t = match rest.first().and_then(|x| x.as_int()) {
+                    Some(t) if t >= 0 => std::time::Duration::from_millis(t as u64),
+                    _ => {
+                        return Ok(
+                            ReplyError::err("timeout is not an integer or out of range").into()
+                        )
+                    }
+                };
+                let all = match rest.get(1).and_then(|x| x.as_string()) {
+                    None if rest.len() == 1 => true,
+                    Some(x) if rest.len() == 2 && x.eq_ignore_ascii_case("all") => true,
+                    Some(x) if rest.len() == 2 && x.eq_ignore_ascii_case("write") => false,
+                    _ => return syntax(),
+                };
+                ctx.pause(t, all);
+                Ok(Value::ok())
+            }
+            _ => {
+                ctx.unpause();
+                Ok(Value::ok())
+            }
+        }
+    }
+
+    /// Return the connection to its initial state: discard any transaction, remove all
+    /// subscriptions, clear the name, switch to RESP2 and authenticate as the default user
+    fn handle_reset(&mut self, client: &mut Client) -> Result<Value, Error> {
+        client.multi = None;
+        client.name = None;
+        client.subscriptions.clear();
+        client.patterns.clear();
+        if let Some(ctx) = client.context.clone() {
+            ctx.unwatch(client);
+            ctx.broker().remove_client(client.id());
+            ctx.monitors().remove(client.id());
+        }
+
+        client.output.set_protocol(2);
+        client.db = 0;
+        client.principal = None;
+        client.authenticated = false;
+        self.auto_authenticate(client);
+
+        Ok(Value::String("RESET".into()))
+    }
+
+    // Connections are authenticated as the default user when it doesn't need a password
+    fn auto_authenticate(&self, client: &mut Client) {
+        if client.authenticated || self.password_required() {
+            return;
+        }
+
+        let nopass = match client.context() {
+            Some(ctx) => ctx.acl().authenticate("default", "", true),
+            None => true,
+        };
+        if nopass {
+            client.authenticated = true;
+            client.principal = Some(Principal::new("default"));
+        }
+    }
+
     fn handle_ping(&mut self, _client: &mut Client, args: &mut Vec<Value>) -> Result<Value, Error> {
-        if args.len() > 0 {
+        if !args.is_empty() {
             Ok(args[0].clone())
         } else {
             Ok("PONG".into())
         }
     }
 
-    async fn handle(
-        handle: Handle<Self>,
-        client: &mut Client,
-        mut command: Command,
-    ) -> anyhow::Result<Value> {
-        {
-            log::info!("command: ({}) {:?}", client.addrs()[0], command);
+    fn handle_acl(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        let ctx = match client.context.clone() {
+            Some(ctx) => ctx,
+            None => return Ok(ReplyError::err("ACL is not available").into()),
+        };
+
+        let sub = args
+            .first()
+            .and_then(|x| x.as_string())
+            .map(|x| x.to_ascii_lowercase());
+        let name = args.get(1).and_then(|x| x.as_string());
+        match (sub.as_deref(), name) {
+            (Some("whoami"), _) => Ok(client.user().unwrap_or("default").into()),
+            (Some("users"), _) => Ok(Value::Array(
+                ctx.acl()
+                    .users()
+                    .iter()
+                    .map(|u| u.name().into())
+                    .collect(),
+            )),
+            (Some("list"), _) => Ok(Value::Array(
+                ctx.acl()
+                    .users()
+                    .iter()
+                    .map(|u| u.describe().into())
+                    .collect(),
+            )),
+            (Some("setuser"), Some(name)) => {
+                let rules = match args[2..]
+                    .iter()
+                    .map(|x| x.as_string())
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(x) => x,
+                    None => return Ok(ReplyError::err("invalid ACL rule").into()),
+                };
+                match ctx.acl().set_user(name, rules) {
+                    Ok(()) => Ok(Value::ok()),
+                    Err(e) => Ok(e.into()),
+                }
+            }
+            (Some("getuser"), Some(name)) => {
+                Ok(ctx.acl().user(name).map(Value::from).unwrap_or(Value::Null))
+            }
+            (Some("deluser"), Some(_)) => {
+                let names = args[1..].iter().filter_map(|x| x.as_string());
+                if names.clone().any(|x| x == "default") {
+                    return Ok(ReplyError::err("The 'default' user cannot be removed").into());
+                }
+                let mut n = 0;
+                for name in names.filter(|x| ctx.acl().del_user(x)) {
+                    // Other connections authenticated as the user are closed
+                    ctx.kill(&ClientFilter {
+                        user: Some(name.into()),
+                        skip: Some(client.id()),
+                        ..ClientFilter::default()
+                    });
+                    n += 1;
+                }
+                Ok(Value::from(n))
+            }
+            (Some("cat"), None) => {
+                let mut cats: std::collections::BTreeSet<&str> =
+                    acl::CATEGORIES.iter().copied().collect();
+                for cmd in self.commands() {
+                    cats.extend(self.categories(cmd));
+                }
+                Ok(Value::Array(cats.into_iter().map(Value::from).collect()))
+            }
+            (Some("cat"), Some(cat)) => {
+                let cat = cat.to_ascii_lowercase();
+                let mut cmds = Vec::new();
+                for name in BUILTIN_COMMANDS
+                    .iter()
+                    .chain(pubsub::COMMANDS)
+                    .chain(transaction::COMMANDS)
+                    .chain(acl::COMMANDS)
+                    .chain(ratelimit::COMMANDS)
+                    .chain(slowlog::COMMANDS)
+                {
+                    let builtin = acl::builtin_categories(&Command::new(*name)).unwrap_or(&[]);
+                    if cat == "all" || builtin.contains(&cat.as_str()) {
+                        cmds.push(Value::from(*name));
+                    }
+                }
+                for name in self.commands() {
+                    if cat == "all" || self.categories(name).contains(&cat.as_str()) {
+                        cmds.push(Value::from(*name));
+                    }
+                }
+                Ok(Value::Array(cmds))
+            }
+            _ => Ok(ReplyError::err(
+                "unknown subcommand, expected WHOAMI, USERS, LIST, SETUSER, GETUSER, DELUSER or CAT",
+            )
+            .into()),
+        }
+    }
+
+    // Start sending every command executed by any connection to this one
+    fn handle_monitor(&mut self, client: &mut Client) -> Result<Value, Error> {
+        match client.context.clone() {
+            Some(ctx) => {
+                client.monitor = Some(ctx.monitors().add(client.id()));
+                Ok(Value::ok())
+            }
+            None => Ok(ReplyError::err("MONITOR is not available").into()),
+        }
+    }
+
+    fn handle_select(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        let db = match args {
+            [db] => client.context.clone().unwrap_or_default().db_index(db),
+            _ => return Error::invalid_args("select", args.len(), 1),
+        };
+
+        match db {
+            Ok(db) => {
+                client.db = db;
+                Ok(Value::ok())
+            }
+            Err(e) => Ok(e.into()),
+        }
+    }
+
+    fn handle_slowlog(&mut self, client: &mut Client, args: &[Value]) -> Result<Value, Error> {
+        let ctx = match client.context.clone() {
+            Some(ctx) => ctx,
+            None => return Ok(ReplyError::err("SLOWLOG is not available").into()),
+        };
+
+        let sub = args
+            .first()
+            .and_then(|x| x.as_string())
+            .map(|x| x.to_ascii_lowercase());
+        match (sub.as_deref(), args.get(1)) {
+            (Some("get"), None) if args.len() == 1 => Ok(ctx.slowlog().get(Some(10))),
+            (Some("get"), Some(n)) if args.len() == 2 => match n.as_int() {
+                Some(-1) => Ok(ctx.slowlog().get(None)),
+                Some(n) if n >= 0 => Ok(ctx.slowlog().get(Some(n as usize))),
+                _ => Ok(ReplyError::err("count should be greater than or equal to -1").into()),
+            },
+            (Some("len"), None) => Ok(Value::from(ctx.slowlog().len() as i64)),
+            (Some("reset"), None) => {
+                ctx.slowlog().reset();
+                Ok(Value::ok())
+            }
+            _ => Ok(ReplyError::err(
+                "unknown subcommand or wrong number of arguments, expected GET, LEN or RESET",
+            )
+            .into()),
+        }
+    }
+
+    fn handle_info(&mut self, client: &mut Client, args: &[Value]) -> Result<Response, Error> {
+        let args: Vec<String> = args
+            .iter()
+            .filter_map(|x| x.as_string().map(String::from))
+            .collect();
+
+        let mut info = Info::new();
+        if let Some(ctx) = client.context() {
+            ctx.info(&mut info);
+        }
+        self.info(&mut info);
+
+        // Like Redis, RESP3 connections receive a verbatim string, which clients can display as-is
+        let text = info.render(&args);
+        if client.output.protocol() < 3 {
+            return Ok(Value::String(text).into());
+        }
+        Ok(Response::raw(format!(
+            "={}\r\ntxt:{}\r\n",
+            text.len() + 4,
+            text
+        )))
+    }
+
+    fn handle_ratelimit(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        let ctx = match client.context.clone() {
+            Some(ctx) => ctx,
+            None => return Ok(ReplyError::err("rate limits are not available").into()),
+        };
+
+        let sub = args
+            .first()
+            .and_then(|x| x.as_string())
+            .map(|x| x.to_ascii_lowercase());
+        match (sub.as_deref(), args.len()) {
+            (Some("info"), 1) | (None, 0) => Ok(ctx.limiter().info()),
+            (Some("reset"), 1) => {
+                ctx.limiter().reset();
+                Ok(Value::ok())
+            }
+            _ => Ok(ReplyError::err("unknown subcommand, expected INFO or RESET").into()),
+        }
+    }
+
+    fn handle_multi(&mut self, client: &mut Client) -> Result<Value, Error> {
+        if client.multi.is_some() {
+            return Ok(ReplyError::err("MULTI calls can not be nested").into());
+        }
+
+        client.multi = Some(Default::default());
+        Ok(Value::ok())
+    }
+
+    fn handle_queue(&mut self, client: &mut Client, command: Command) -> Result<Value, Error> {
+        let name = command.name();
+        let known = is_known(self, name);
+
+        let tx = match &mut client.multi {
+            Some(tx) => tx,
+            None => return Ok(ReplyError::err("no transaction in progress").into()),
+        };
+
+        if !known {
+            tx.error = true;
+            return Ok(ReplyError::err(format!("unknown command '{}'", name)).into());
+        }
+
+        if transaction::NOT_ALLOWED.contains(&name) {
+            tx.error = true;
+            return Ok(ReplyError::err(format!(
+                "Command '{}' not allowed inside a transaction",
+                name
+            ))
+            .into());
+        }
 
-            let mut x = handle.lock().await;
-            if !client.authenticated && !x.password_required() {
-                client.authenticated = true
+        tx.commands.push(command);
+        Ok("QUEUED".into())
+    }
+
+    fn handle_discard(&mut self, client: &mut Client) -> Result<Value, Error> {
+        if client.multi.take().is_none() {
+            return Ok(ReplyError::err("DISCARD without MULTI").into());
+        }
+
+        if let Some(ctx) = client.context.clone() {
+            ctx.unwatch(client);
+        }
+
+        Ok(Value::ok())
+    }
+
+    fn handle_watch(&mut self, client: &mut Client, args: &[Value]) -> anyhow::Result<Value> {
+        if client.multi.is_some() {
+            return Ok(ReplyError::err("WATCH inside MULTI is not allowed").into());
+        }
+
+        if args.is_empty() {
+            return Error::invalid_args("watch", 0, 1);
+        }
+
+        if let Some(ctx) = client.context.clone() {
+            for key in args {
+                ctx.watch(client, key.clone());
             }
+        }
+
+        Ok(Value::ok())
+    }
+
+    fn handle_unwatch(&mut self, client: &mut Client) -> Result<Value, Error> {
+        if let Some(ctx) = client.context.clone() {
+            ctx.unwatch(client);
+        }
+
+        Ok(Value::ok())
+    }
+
+    async fn handle_exec(&mut self, client: &mut Client) -> anyhow::Result<Value> {
+        let tx = match client.multi.take() {
+            Some(tx) => tx,
+            None => return Ok(ReplyError::err("EXEC without MULTI").into()),
+        };
+
+        let dirty = client.dirty.load(std::sync::atomic::Ordering::SeqCst);
+        if let Some(ctx) = client.context.clone() {
+            ctx.unwatch(client);
+        }
+
+        if tx.error {
+            return Ok(Value::error(
+                "EXECABORT Transaction discarded because of previous errors",
+            ));
+        }
 
-            match command.name() {
-                "hello" => return x.handle_hello(client, command.args()),
-                "auth" => return x.handle_auth(client, command.args()),
-                _ if !client.authenticated => return Error::disconnect("ERR invalid handshake"),
-                "commands" => return x.handle_commands(client, command.args()).map_err(Into::into),
-                "ping" => return x.handle_ping(client, command.args_mut()).map_err(Into::into),
-                _ => (),
+        // A watched key was modified
+        if dirty {
+            return Ok(Value::Null);
+        }
+
+        let mut results = Vec::with_capacity(tx.commands.len());
+        for command in tx.commands {
+            let res = match self.execute(client, command).await {
+                Ok(x) => x.into_value().await?,
+                Err(e) => error_reply(e),
+            };
+            results.push(res);
+        }
+
+        Ok(Value::Array(results))
+    }
+
+    /// Execute a single command, the handler lock is held by the caller
+    async fn execute(&mut self, client: &mut Client, mut command: Command) -> HandlerResult {
+        self.auto_authenticate(client);
+
+        match command.name() {
+            // Rejected by `handle_queue`, they're never executed inside a transaction
+            "hello" | "auth" if client.multi.is_some() => {
+                return Ok(self.handle_queue(client, command)?.into())
+            }
+            "hello" => return self.handle_hello(client, command.args()).map(Into::into),
+            "auth" => return self.handle_auth(client, command.args()).map(Into::into),
+            _ if !client.authenticated => return Error::disconnect("ERR invalid handshake"),
+            name if client.output.protocol() < 3
+                && client.subscription_count() > 0
+                && !pubsub::SUBSCRIBED_COMMANDS.contains(&name) =>
+            {
+                return Ok(ReplyError::err(format!(
+                    "Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                    name
+                ))
+                .into());
             }
+            _ => (),
         }
 
-        if !client.authenticated {
-            return Error::disconnect("ERR unauthorized");
+        if let Err(e) = self.check_permissions(client, &command) {
+            // Commands rejected while queueing abort the transaction
+            if let Some(tx) = &mut client.multi {
+                tx.error = true;
+            }
+            return Ok(e.into());
         }
 
-        log::info!("command: ({}) {:?}", client.addrs()[0], command);
+        match command.name() {
+            "reset" => return Ok(self.handle_reset(client)?.into()),
+            "multi" => return Ok(self.handle_multi(client)?.into()),
+            "exec" => return self.handle_exec(client).await.map(Into::into),
+            "discard" => return Ok(self.handle_discard(client)?.into()),
+            "watch" => return self.handle_watch(client, command.args()).map(Into::into),
+            _ if client.multi.is_some() => return Ok(self.handle_queue(client, command)?.into()),
+            "unwatch" => return Ok(self.handle_unwatch(client)?.into()),
+            "commands" => return Ok(self.handle_commands(client, command.args())?.into()),
+            "ping" => return Ok(self.handle_ping(client, command.args_mut())?.into()),
+            "select" => return self.handle_select(client, command.args()).map(Into::into),
+            "acl" => return self.handle_acl(client, command.args()).map(Into::into),
+            "client" => return self.handle_client(client, command.args()).map(Into::into),
+            "ratelimit" => {
+                return self
+                    .handle_ratelimit(client, command.args())
+                    .map(Into::into)
+            }
+            "info" => return Ok(self.handle_info(client, command.args())?),
+            "monitor" => return Ok(self.handle_monitor(client)?.into()),
+            "slowlog" => return Ok(self.handle_slowlog(client, command.args())?.into()),
+            _ => (),
+        }
 
-        let value = Self::call(handle, std::pin::Pin::new(client), command).await?;
-        Ok(value)
+        if pubsub::COMMANDS.contains(&command.name()) {
+            return pubsub::handle(client, command).await;
+        }
 
+        self.dispatch(std::pin::Pin::new(client), command).await
     }
 }
 
-async fn on_command<T: Handler>(data: Handle<T>, client: &mut Client) -> Result<bool, Error> {
-    let value = client.read().await?;
-    let mut response = true;
+#[cfg(feature = "metrics")]
+struct AbortOnDrop(futures::future::AbortHandle);
+
+#[cfg(feature = "metrics")]
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
 
+// Convert a decoded value into a command, anything else is ignored
+fn to_command(value: Value) -> Option<Command> {
     if let Value::Array(mut cmd) = value {
-        let name = cmd.remove(0);
-        if let Value::String(s) = name {
-            let cmd = Command::new(s).with_args(cmd);
-            let res = match T::handle(data, client, cmd).await  {
-                Ok(x) => x,
-                Err(e) =>  match e.downcast::<Error>()  {
-                    Ok(Error::Disconnect(e)) => {
-                        log::info!("disconnect: ({}) {:?}", client.addrs()[0], e);
-                        response = false;
-                        Value::Error(e)
-                    }
-                    Ok(Error::Done) => {
-                        client.flush().await?;
-                        return Ok(true)
-                    }
-                    Ok(e) => Err(e).into(),
-                    Err(e) => Err(e).into(),
+        if cmd.is_empty() {
+            return None;
+        }
+
+        if let Value::String(s) = cmd.remove(0) {
+            return Some(Command::new(s).with_args(cmd));
+        }
+    }
+
+    None
+}
+
+// Convert an error returned while executing a command into an error reply
+pub(crate) fn error_reply(e: anyhow::Error) -> Value {
+    match e.downcast::<ReplyError>() {
+        Ok(e) => e.into(),
+        Err(e) => match e.downcast::<Error>() {
+            Ok(Error::Disconnect(e)) => Value::Error(e),
+            Ok(Error::Reply(e)) => e.into(),
+            Ok(e) => Err(e).into(),
+            Err(e) => Err(e).into(),
+        },
+    }
+}
+
+// Whether a command is built in or implemented by the handler
+fn is_known<T: Handler>(handler: &T, name: &str) -> bool {
+    handler.has_command(name)
+        || BUILTIN_COMMANDS.contains(&name)
+        || pubsub::COMMANDS.contains(&name)
+        || transaction::COMMANDS.contains(&name)
+        || acl::COMMANDS.contains(&name)
+        || ratelimit::COMMANDS.contains(&name)
+        || slowlog::COMMANDS.contains(&name)
+}
+
+// Get the message passed to `panic!`, if there is one
+fn panic_message(e: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        return s;
+    }
+
+    match e.downcast_ref::<String>() {
+        Some(s) => s.as_str(),
+        None => "unknown",
+    }
+}
+
+// Check credentials using an authenticator, a panic is reported as an `ERR internal error` reply
+pub(crate) async fn verify(
+    authenticator: &dyn Authenticator,
+    username: &str,
+    password: &str,
+    addr: std::net::SocketAddr,
+) -> Result<Principal, ReplyError> {
+    use futures::FutureExt;
+
+    std::panic::AssertUnwindSafe(authenticator.authenticate(username, password))
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("panic: ({}) {}", addr, panic_message(e.as_ref()));
+            Err(ReplyError::err("internal error"))
+        })
+}
+
+// Execute a single command and return its reply, a panic in the handler is reported to the client
+// as an `ERR internal error` reply, the flag is `false` when the client should be disconnected.
+// Streamed and deferred replies are resolved after releasing the handler lock, the lock is
+// acquired again before returning
+async fn on_command<'a, T: Handler>(
+    data: &'a Handle<T>,
+    mut handler: tokio::sync::MutexGuard<'a, T>,
+    client: &mut Client,
+    command: Command,
+    options: &Options,
+) -> (Response, bool, tokio::sync::MutexGuard<'a, T>) {
+    log::info!(
+        "command: ({}) {:?}",
+        client.addrs()[0],
+        monitor::redact(&command)
+    );
+
+    use futures::FutureExt;
+
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+        "command",
+        name = command.name(),
+        duration_us = tracing::field::Empty,
+        failed = tracing::field::Empty,
+    );
+    #[cfg(feature = "tracing")]
+    tracing::debug!(parent: &span, args = ?monitor::redact(&command).args());
+
+    let mut response = true;
+    let addr = client.addrs()[0];
+    let start = std::time::Instant::now();
+    let name = Some(command.name().to_string()).filter(|x| is_known(&*handler, x));
+    if let Some(ctx) = client.context() {
+        ctx.update(client, Some(command.name()));
+        ctx.monitors().feed(client, &command);
+    }
+    let slowlog = options
+        .config
+        .slowlog_threshold
+        .map(|_| slowlog::Pending::new(&command));
+    let conn = &mut *client;
+    let exec = async move {
+        let next = Next::new(&options.middleware, &mut *handler);
+        match next.run(conn, command).await {
+            // Resolved here so the command timeout and panic handling apply to them
+            Ok(x) if x.is_deferred() => {
+                drop(handler);
+                (x.resolve().await, None)
+            }
+            res => (res, Some(handler)),
+        }
+    };
+    let exec = std::panic::AssertUnwindSafe(exec).catch_unwind();
+    #[cfg(feature = "tracing")]
+    let exec = tracing::Instrument::instrument(exec, span.clone());
+    let res = match options.config.command_timeout {
+        Some(t) => tokio::time::timeout(t, exec).await.unwrap_or_else(|_| {
+            log::warn!("command timed out: ({})", addr);
+            Ok((Ok(ReplyError::err("command timed out").into()), None))
+        }),
+        None => exec.await,
+    };
+    let (res, handler) = res.unwrap_or_else(|e| {
+        log::error!("panic: ({}) {}", addr, panic_message(e.as_ref()));
+        (Ok(ReplyError::err("internal error").into()), None)
+    });
+    let handler = match handler {
+        Some(x) => x,
+        None => data.lock().await,
+    };
+    let res = match res {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(Error::Disconnect(e)) = e.downcast_ref::<Error>() {
+                log::info!("disconnect: ({}) {:?}", client.addrs()[0], e);
+                response = false;
+            }
+            Response::Value(error_reply(e))
+        }
+    };
+    let failed = matches!(res, Response::Value(Value::Error(_)));
+    let elapsed = start.elapsed();
+    #[cfg(feature = "tracing")]
+    span.record("duration_us", elapsed.as_micros() as u64)
+        .record("failed", failed);
+    if let Some(ctx) = client.context() {
+        ctx.stats().record(name.as_deref(), elapsed, failed);
+        #[cfg(feature = "metrics")]
+        ctx.recorder()
+            .command(name.as_deref().unwrap_or("unknown"), elapsed, failed);
+        ctx.update(client, None);
+        match (options.config.slowlog_threshold, slowlog) {
+            (Some(t), Some(pending)) if elapsed >= t => ctx.slowlog().record(
+                client,
+                pending.args(),
+                elapsed,
+                options.config.slowlog_max_len,
+            ),
+            _ => (),
+        }
+    }
+
+    (res, response, handler)
+}
+
+// Take a token from each rate limit bucket that applies to a command
+fn rate_limit<T: Handler>(
+    handler: &T,
+    client: &Client,
+    command: &Command,
+    options: &Options,
+) -> ratelimit::Decision {
+    let ctx = match client.context() {
+        Some(ctx) if !options.config.rate_limits.is_empty() => ctx,
+        _ => return ratelimit::Decision::Allow,
+    };
+
+    ctx.limiter().acquire(
+        client.id(),
+        client.user().unwrap_or("default"),
+        categories(handler, command),
+    )
+}
+
+// ACL categories of a built-in or handler command
+fn categories<'a, T: Handler>(handler: &'a T, command: &Command) -> &'a [&'a str] {
+    match acl::builtin_categories(command) {
+        Some(x) => x,
+        None => handler.categories(command.name()),
+    }
+}
+
+// Execute every command that has already been received, up to `max_batch`, holding the handler
+// lock once. The replies are written and flushed together after the lock is released, so a client
+// that doesn't read its replies can't stall other connections
+async fn on_batch<T: Handler>(
+    data: Handle<T>,
+    client: &mut Client,
+    options: &Options,
+) -> Result<bool, Error> {
+    let mut value = config::timeout(options.config.read_timeout, "read", client.read()).await?;
+    let mut handler = data.lock().await;
+    let mut replies = Vec::new();
+    let mut connected = true;
+    let mut n = 0;
+
+    loop {
+        n += 1;
+
+        if let Some(command) = to_command(value) {
+            // `CLIENT` commands are allowed while paused, so the pause can be ended early
+            let ctx = client
+                .context
+                .clone()
+                .filter(|_| command.name() != "client");
+            if let Some(ctx) = ctx {
+                let write = categories(&*handler, &command).contains(&"write");
+                if ctx.paused(write).is_some() {
+                    drop(handler);
+                    ctx.wait_unpaused(write).await;
+                    handler = data.lock().await;
                 }
+            }
+
+            let rejected = match rate_limit(&*handler, client, &command, options) {
+                ratelimit::Decision::Allow => None,
+                ratelimit::Decision::Wait(t) => {
+                    // Stop reading from the connection without blocking other clients
+                    drop(handler);
+                    tokio::time::sleep(t).await;
+                    handler = data.lock().await;
+                    None
+                }
+                ratelimit::Decision::Reject(e) => Some(e),
             };
-            client.write(&res).await?;
-            client.flush().await?;
+
+            if let Some(e) = rejected {
+                log::debug!("rate limited: ({}) {}", client.addrs()[0], command.name());
+                if let Some(tx) = &mut client.multi {
+                    tx.error = true;
+                }
+                replies.push((client.output.protocol(), Response::from(e)));
+            } else {
+                // The authenticator runs without holding the handler lock, the result is used by
+                // `Handler::authenticate` while executing the command
+                let authenticator = match auth::credentials(&command) {
+                    Some(creds) if client.multi.is_none() => {
+                        handler.authenticator().map(|x| (x, creds))
+                    }
+                    _ => None,
+                };
+                if let Some((authenticator, (username, password))) = authenticator {
+                    drop(handler);
+                    let addr = client.addrs()[0];
+                    let res = verify(&*authenticator, username, password, addr).await;
+                    client.verified = Some(res);
+                    handler = data.lock().await;
+                }
+
+                let (res, ok, guard) = on_command(&data, handler, client, command, options).await;
+                handler = guard;
+                client.verified = None;
+                // Commands like `HELLO` change the protocol, each reply is encoded with the
+                // protocol that was in use when it was produced
+                replies.push((client.output.protocol(), res));
+                if !ok {
+                    connected = false;
+                    break;
+                }
+            }
+        } else {
+            #[cfg(feature = "metrics")]
+            if let Some(ctx) = client.context() {
+                ctx.recorder().protocol_error();
+            }
         }
+
+        if n >= options.config.max_batch || !client.has_buffered_value() {
+            break;
+        }
+
+        value = client.read().await?;
     }
 
-    Ok(response)
+    drop(handler);
+    let protocol = client.output.protocol();
+    let write = async {
+        for (protocol, res) in replies {
+            client.output.set_protocol(protocol);
+            res.write(&mut client.output).await?;
+        }
+        client.output.set_protocol(protocol);
+        client.flush().await
+    };
+    config::timeout(options.config.write_timeout, "write", write).await?;
+    Ok(connected)
+}
+
+async fn run_client<T: Handler>(
+    data: std::sync::Arc<tokio::sync::Mutex<T>>,
+    client: &mut Client,
+    mut push: pubsub::Receiver,
+    options: &Options,
+) {
+    async fn idle(t: Option<std::time::Duration>) {
+        match t {
+            Some(t) => tokio::time::sleep(t).await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    let mut monitor = None;
+
+    loop {
+        if client.monitor.is_some() {
+            monitor = client.monitor.take();
+        }
+
+        // Connections waiting for pub/sub messages or monitoring are never idle
+        let idle_timeout = if client.subscription_count() > 0 || monitor.is_some() {
+            None
+        } else {
+            options.config.idle_timeout
+        };
+
+        // Wait for either a new command or a message queued for this connection, pushes are only
+        // written between commands so they never interleave with a reply
+        let msg = tokio::select! {
+            ready = client.readable() => match ready {
+                Ok(true) => None,
+                Ok(false) => {
+                    log::debug!("disconnecting: {}", client.addrs()[0]);
+                    break;
+                }
+                Err(e) => {
+                    log::debug!("fatal error: {:?}", e);
+                    break;
+                }
+            },
+            msg = push.recv() => match msg {
+                None => {
+                    log::debug!("push buffer full, disconnecting: {}", client.addrs()[0]);
+                    break;
+                }
+                // RESP2 has no push type, so only pub/sub messages can be told apart from replies
+                Some(Value::Push(kind, _))
+                    if client.output.protocol() < 3 && kind != "message" && kind != "pmessage" =>
+                {
+                    continue
+                }
+                msg => msg,
+            },
+            line = monitor::next(&mut monitor) => match line {
+                Some(line) => Some(Value::String(line)),
+                None => {
+                    // Stopped by `RESET`
+                    monitor = None;
+                    continue;
+                }
+            },
+            _ = idle(idle_timeout) => {
+                log::debug!("idle timeout: {}", client.addrs()[0]);
+                break;
+            }
+        };
+
+        let res = match msg {
+            Some(msg) => {
+                let write = async {
+                    client.write(&msg).await?;
+                    client.flush().await?;
+                    Ok(true)
+                };
+                config::timeout(options.config.write_timeout, "write", write).await
+            }
+            None => on_batch(Handle(data.clone()), client, options).await,
+        };
+
+        match res {
+            Ok(true) => continue,
+            Ok(false) => {
+                log::debug!("disconnecting: {}", client.addrs()[0]);
+                break;
+            }
+            Err(e) => {
+                log::debug!("fatal error: {:?}", e);
+                break;
+            }
+        }
+    }
 }
 
 impl<T: 'static + Handler + Send> Server<T> {
     pub fn new(data: T) -> Self {
-        Server { data }
+        Server {
+            data,
+            context: Default::default(),
+            config: Default::default(),
+            hooks: Default::default(),
+            middleware: Vec::new(),
+            shutdown: None,
+            listeners: Vec::new(),
+            #[cfg(feature = "metrics")]
+            metrics_listener: None,
+        }
     }
 
+    pub fn builder(data: T) -> ServerBuilder<T> {
+        ServerBuilder {
+            server: Server::new(data),
+        }
+    }
+
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Maximum number of pipelined commands executed before replies are flushed, defaults to 1024
+    pub fn max_batch(mut self, n: usize) -> Self {
+        self.config.max_batch = n.max(1);
+        self
+    }
+
+    /// Add middleware around command execution, see `Middleware`
+    pub fn middleware(mut self, m: impl Middleware) -> Self {
+        self.middleware.push(std::sync::Arc::new(m));
+        self
+    }
+
+    /// Server-wide state, can be used to publish or push messages from outside of a handler
+    pub fn context(&self) -> ServerContext {
+        self.context.clone()
+    }
+
+    /// Accept connections from a listener that is already bound, in addition to the addresses in
+    /// the config. Binding to port 0 and reading the listener's `local_addr` first lets the system
+    /// pick a free port, and clients can connect as soon as the listener is bound
+    pub fn listener(mut self, listener: tokio::net::TcpListener) -> Self {
+        self.listeners.push(stream::Listener::Tcp(listener));
+        self
+    }
+
+    /// Listen on `addr` in addition to the addresses in the config
     pub async fn run<A: tokio::net::ToSocketAddrs>(self, addr: A) -> Result<(), Error> {
-        let conn = tokio::net::TcpListener::bind(addr).await?;
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        self.listener(listener).listen().await
+    }
+
+    /// Listen on the addresses in the config and the listeners added using `listener`
+    pub async fn serve(self) -> Result<(), Error> {
+        self.listen().await
+    }
+
+    async fn listen(mut self) -> Result<(), Error> {
+        check_config(&self.config, self.listeners.len())?;
+        let mut listeners = std::mem::take(&mut self.listeners);
+        if let Some(level) = self.config.log_level {
+            log::set_max_level(level);
+        }
+
+        apply_users(&self.config, self.context.acl())?;
+        self.context.set_databases(self.config.databases);
+        self.context
+            .limiter()
+            .configure(self.config.rate_limits.clone());
+
+        for addr in &self.config.bind {
+            listeners.push(stream::Listener::bind(addr).await?);
+        }
+
+        #[cfg(feature = "metrics")]
+        let metrics_listener = match (self.metrics_listener.take(), &self.config.metrics_bind) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(addr)) => Some(tokio::net::TcpListener::bind(addr).await?),
+            (None, None) => None,
+        };
+
+        // Stopped along with the server
+        #[cfg(feature = "metrics")]
+        let _metrics = match metrics_listener {
+            Some(listener) => {
+                let (serve, handle) =
+                    futures::future::abortable(metrics::serve(listener, self.context.clone()));
+                tokio::spawn(serve);
+                Some(AbortOnDrop(handle))
+            }
+            None => None,
+        };
+
+        let mut shutdown = self
+            .shutdown
+            .take()
+            .unwrap_or_else(|| Box::pin(futures::future::pending()));
         let data = std::sync::Arc::new(tokio::sync::Mutex::new(self.data));
+        let options = std::sync::Arc::new(Options {
+            config: self.config,
+            middleware: self.middleware,
+        });
+        let hooks = std::sync::Arc::new(self.hooks);
+        let clients = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
         loop {
-            let (socket, addr) = conn.accept().await?;
+            let accept =
+                futures::future::select_all(listeners.iter().map(|x| Box::pin(x.accept())));
+            let (mut socket, addr) = tokio::select! {
+                (res, _, _) = accept => match res {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::error!("unable to accept connection: {:?}", e);
+                        tokio::time::sleep(stream::ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => {
+                    log::info!("shutting down");
+                    return Ok(());
+                }
+            };
+
             let data = data.clone();
+            let context = self.context.clone();
+            let options = options.clone();
+            let hooks = hooks.clone();
+            let clients = clients.clone();
             tokio::spawn(async move {
-                let mut client = Client::new_from_stream(socket, vec![addr], None)
-                    .await
-                    .unwrap();
-                loop {
-                    match on_command(Handle(data.clone()), &mut client).await {
-                        Ok(true) => continue,
-                        Ok(false) => {
-                            log::debug!("disconnecting: {}", client.addrs()[0]);
-                            break;
+                use std::sync::atomic::Ordering;
+
+                if clients.fetch_add(1, Ordering::SeqCst) >= options.config.max_clients {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    info::Stats::incr(&context.stats().rejected_connections, 1);
+                    log::warn!("max number of clients reached: ({})", addr);
+                    let _ = socket
+                        .write_all(b"-ERR max number of clients reached\r\n")
+                        .await;
+                    return;
+                }
+
+                if let Err(e) = options.config.configure(&socket) {
+                    log::error!("unable to configure socket: ({}) {:?}", addr, e);
+                }
+
+                match Client::new_from_stream(socket, vec![addr], None).await {
+                    Ok(mut client) => {
+                        let (tx, rx) = pubsub::channel(options.config.push_buffer);
+                        client.output.set_protocol(options.config.protocol);
+                        let (info, killed) = context.register(&client, tx.clone());
+                        client.context = Some(context.clone());
+                        client.push = Some(tx);
+
+                        for f in &hooks.connect {
+                            f(&info);
+                        }
+
+                        #[cfg(feature = "tracing")]
+                        let span = tracing::info_span!(
+                            "connection",
+                            peer = %addr,
+                            client_id = client.id(),
+                        );
+                        let run = run_client(data, &mut client, rx, &options);
+                        #[cfg(feature = "tracing")]
+                        let run = tracing::Instrument::instrument(run, span);
+
+                        tokio::select! {
+                            _ = run => (),
+                            _ = killed => log::info!("killed: ({})", addr),
                         }
-                        Err(e) => {
-                            log::debug!("fatal error: {:?}", e);
-                            break;
+
+                        for f in &hooks.disconnect {
+                            f(&info);
                         }
+                        context.unregister(&mut client);
                     }
+                    Err(e) => log::error!("unable to accept connection: ({}) {:?}", addr, e),
                 }
+
+                clients.fetch_sub(1, Ordering::SeqCst);
             });
         }
     }
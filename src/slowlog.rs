@@ -0,0 +1,165 @@
+use crate::internal::*;
+
+use std::collections::VecDeque;
+
+pub(crate) const COMMANDS: &[&str] = &["slowlog"];
+
+// Commands are logged with at most this many arguments, each truncated to `MAX_ARG_LEN` bytes
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+struct Entry {
+    id: i64,
+    time: i64,
+    duration: i64,
+    args: Vec<Value>,
+    addr: String,
+    name: String,
+}
+
+impl From<&Entry> for Value {
+    fn from(entry: &Entry) -> Value {
+        Value::Array(vec![
+            entry.id.into(),
+            entry.time.into(),
+            entry.duration.into(),
+            Value::Array(entry.args.clone()),
+            entry.addr.as_str().into(),
+            entry.name.as_str().into(),
+        ])
+    }
+}
+
+/// Commands that took longer than `ServerConfig::slowlog_threshold`, newest first
+#[derive(Default)]
+pub(crate) struct SlowLog {
+    entries: std::sync::Mutex<VecDeque<Entry>>,
+    next_id: std::sync::atomic::AtomicU64,
+}
+
+impl SlowLog {
+    pub(crate) fn record(
+        &self,
+        client: &Client,
+        args: Vec<Value>,
+        duration: std::time::Duration,
+        max_len: usize,
+    ) {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let entry = Entry {
+            id: self
+                .next_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed) as i64,
+            time: time.as_secs() as i64,
+            duration: duration.as_micros() as i64,
+            args,
+            addr: client.addrs()[0].to_string(),
+            name: client.name().unwrap_or_default().to_string(),
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(max_len);
+    }
+
+    pub(crate) fn get(&self, n: Option<usize>) -> Value {
+        let entries = self.entries.lock().unwrap();
+        let n = n.unwrap_or(entries.len());
+        Value::Array(entries.iter().take(n).map(Value::from).collect())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub(crate) fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Arguments of a running command, kept until it's known whether the command was slow. Only the
+/// parts that can appear in the log are copied, the entry is built by `Pending::args`
+pub(crate) struct Pending {
+    name: String,
+    // Each argument, or its first `MAX_ARG_LEN` bytes, with its length. Redacted arguments are `None`
+    args: Vec<Option<(Value, usize)>>,
+    total: usize,
+}
+
+impl Pending {
+    pub(crate) fn new(command: &Command) -> Pending {
+        let redacted = monitor::redacted(command);
+        let all = command.args();
+        let n = if all.len() + 1 > MAX_ARGS {
+            MAX_ARGS - 2
+        } else {
+            all.len()
+        };
+
+        let args = all
+            .iter()
+            .take(n)
+            .enumerate()
+            .map(|(i, arg)| {
+                if redacted.contains(&i) {
+                    return None;
+                }
+
+                Some(match arg {
+                    Value::String(s) if s.len() > MAX_ARG_LEN => {
+                        (Value::Bytes(s.as_bytes()[..MAX_ARG_LEN].to_vec()), s.len())
+                    }
+                    Value::Bytes(b) if b.len() > MAX_ARG_LEN => {
+                        (Value::Bytes(b[..MAX_ARG_LEN].to_vec()), b.len())
+                    }
+                    x => (x.clone(), 0),
+                })
+            })
+            .collect();
+
+        Pending {
+            name: command.name().to_string(),
+            args,
+            total: all.len(),
+        }
+    }
+
+    // Command name and arguments as they're stored in the log, long arguments and argument lists
+    // are shortened the same way as Redis and credentials are redacted
+    pub(crate) fn args(self) -> Vec<Value> {
+        let n = self.args.len();
+        let mut args = Vec::with_capacity(n + 2);
+        args.push(Value::from(self.name));
+        for arg in self.args {
+            let (arg, len) = match arg {
+                Some(x) => x,
+                None => {
+                    args.push("(redacted)".into());
+                    continue;
+                }
+            };
+
+            let arg = match arg {
+                Value::String(s) => Value::String(s),
+                Value::Bytes(b) if len > MAX_ARG_LEN => Value::from(format!(
+                    "{}... ({} more bytes)",
+                    String::from_utf8_lossy(&b),
+                    len - MAX_ARG_LEN
+                )),
+                Value::Bytes(b) => Value::from(String::from_utf8_lossy(&b).into_owned()),
+                x => x,
+            };
+            args.push(arg);
+        }
+
+        if n < self.total {
+            args.push(Value::from(format!(
+                "... ({} more arguments)",
+                self.total - n
+            )));
+        }
+        args
+    }
+}
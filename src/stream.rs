@@ -0,0 +1,214 @@
+use crate::internal::*;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Connection to a TCP or Unix socket, or an in-memory pipe
+pub enum Stream {
+    Tcp(tokio::net::TcpStream),
+
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+
+    Memory(tokio::io::DuplexStream),
+}
+
+impl From<tokio::net::TcpStream> for Stream {
+    fn from(x: tokio::net::TcpStream) -> Stream {
+        Stream::Tcp(x)
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::UnixStream> for Stream {
+    fn from(x: tokio::net::UnixStream) -> Stream {
+        Stream::Unix(x)
+    }
+}
+
+impl From<tokio::io::DuplexStream> for Stream {
+    fn from(x: tokio::io::DuplexStream) -> Stream {
+        Stream::Memory(x)
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(x) => Pin::new(x).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(x) => Pin::new(x).poll_read(cx, buf),
+            Stream::Memory(x) => Pin::new(x).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(x) => Pin::new(x).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(x) => Pin::new(x).poll_write(cx, buf),
+            Stream::Memory(x) => Pin::new(x).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(x) => Pin::new(x).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(x) => Pin::new(x).poll_flush(cx),
+            Stream::Memory(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(x) => Pin::new(x).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(x) => Pin::new(x).poll_shutdown(cx),
+            Stream::Memory(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+// Delay before accepting again after an error, errors like `EMFILE` don't clear immediately
+pub(crate) const ACCEPT_ERROR_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+// Socket a server accepts connections on
+pub(crate) enum Listener {
+    Tcp(tokio::net::TcpListener),
+
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Bind to `host:port`, or to a Unix socket when the address starts with `unix:`
+    pub(crate) async fn bind(addr: &str) -> Result<Listener, Error> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::FileTypeExt;
+
+                // Remove the socket left behind by a previous run, any other file is kept and
+                // makes binding fail
+                let meta = std::fs::symlink_metadata(path);
+                if meta.map(|x| x.file_type().is_socket()).unwrap_or(false) {
+                    let _ = std::fs::remove_file(path);
+                }
+                return Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?));
+            }
+
+            #[cfg(not(unix))]
+            return Err(Error::Config(format!(
+                "unix sockets are not supported: {}",
+                path
+            )));
+        }
+
+        Ok(Listener::Tcp(tokio::net::TcpListener::bind(addr).await?))
+    }
+
+    /// Accept a new connection, Unix socket connections use an unspecified address
+    pub(crate) async fn accept(&self) -> Result<(Stream, std::net::SocketAddr), Error> {
+        match self {
+            Listener::Tcp(x) => {
+                let (socket, addr) = x.accept().await?;
+                Ok((socket.into(), addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(x) => {
+                let (socket, _) = x.accept().await?;
+                Ok((socket.into(), ([0, 0, 0, 0], 0).into()))
+            }
+        }
+    }
+}
+
+/// Number of bytes read from and written to a connection
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    pub(crate) read: std::sync::atomic::AtomicU64,
+    pub(crate) written: std::sync::atomic::AtomicU64,
+}
+
+impl Traffic {
+    pub(crate) fn read(&self) -> u64 {
+        self.read.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn written(&self) -> u64 {
+        self.written.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Half of a `Stream` that records the number of bytes transferred
+pub struct Counted<T> {
+    inner: T,
+    traffic: std::sync::Arc<Traffic>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, traffic: std::sync::Arc<Traffic>) -> Counted<T> {
+        Counted { inner, traffic }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        this.traffic
+            .read
+            .fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.traffic
+                .written
+                .fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -17,5 +17,1761 @@ async fn test_roundtrip() -> Result<(), Error> {
 
     let value = Value::read(&mut buffer.as_slice()).await?;
     assert_eq!(ex, value);
+
+    // The length of a verbatim string includes its format
+    let value = Value::read(&mut &b"=9\r\ntxt:hello\r\n:1\r\n"[..]).await?;
+    assert_eq!(value, Value::from("hello"));
+    Ok(())
+}
+
+#[derive(Default, worm::Handler)]
+#[commands(echo, notify, touch, range, raw, fail, panic, sleep)]
+#[info(info)]
+struct Echo;
+
+impl Echo {
+    fn info(&self, info: &mut Info) {
+        info.section("Echo").field("answer", 42);
+    }
+
+    async fn echo(
+        &mut self,
+        _client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
+        Ok(command.pop_front().into())
+    }
+
+    async fn notify(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
+        let ctx = client.context().unwrap();
+        let id = command.pop_front().as_int().unwrap_or_default();
+        let msg = command.pop_front();
+        if id == 0 {
+            return Ok(Value::from(ctx.broadcast("notify", vec![msg]) as i64).into());
+        }
+        Ok(Value::from(ctx.send(id as u64, "notify", vec![msg])).into())
+    }
+
+    async fn touch(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
+        let db = client.db();
+        client.context().unwrap().touch(db, &command.pop_front());
+        Ok(Response::ok())
+    }
+
+    async fn range(
+        &mut self,
+        _client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
+        use futures::StreamExt;
+
+        let n = command.pop_front().as_int().unwrap_or_default();
+        let items = futures::stream::iter(0..n).map(Value::from);
+
+        match command.pop_front().as_string() {
+            // Filtering hides the exact size, so the stream has to be collected
+            Some("filter") => Ok(Response::stream(items.filter(|_| async { true }))),
+            Some("panic") => Ok(Response::stream(
+                items.chain(futures::stream::once(async { panic!("test panic") })),
+            )),
+            Some("pending") => Ok(Response::stream(items.chain(futures::stream::pending()))),
+            _ => Ok(Response::stream(items)),
+        }
+    }
+
+    async fn raw(
+        &mut self,
+        _client: std::pin::Pin<&mut Client>,
+        _command: Command,
+    ) -> HandlerResult {
+        Ok(Response::raw(&b"+raw\r\n"[..]))
+    }
+
+    async fn fail(
+        &mut self,
+        _client: std::pin::Pin<&mut Client>,
+        _command: Command,
+    ) -> HandlerResult {
+        Err(ReplyError::wrong_type().into())
+    }
+
+    async fn panic(
+        &mut self,
+        _client: std::pin::Pin<&mut Client>,
+        _command: Command,
+    ) -> HandlerResult {
+        panic!("test panic")
+    }
+
+    async fn sleep(
+        &mut self,
+        _client: std::pin::Pin<&mut Client>,
+        mut command: Command,
+    ) -> HandlerResult {
+        let ms = command.pop_front().as_int().unwrap_or_default();
+        tokio::time::sleep(std::time::Duration::from_millis(ms as u64)).await;
+        Ok(Response::ok())
+    }
+}
+
+// Bind to a free port, connections are queued by the listener until the server accepts them so
+// clients can connect as soon as this returns
+async fn bind() -> (tokio::net::TcpListener, String) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+async fn start_server() -> String {
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(Echo).listener(listener).serve());
+    addr
+}
+
+#[tokio::test]
+async fn test_hello() -> Result<(), Error> {
+    let addr = start_server().await;
+
+    let client = Client::connect(&addr, Hello::new().setname("test")).await?;
+    let info = client.server_info().unwrap().clone();
+    assert_eq!(info.server, "worm");
+    assert_eq!(info.proto, 3);
+    assert_eq!(info.mode, "standalone");
+    assert!(info.id > 0);
+
+    let mut client = Client::connect(&addr, Hello::new().protover(2)).await?;
+    assert_eq!(client.server_info().unwrap().proto, 2);
+    assert_eq!(client.command(&["echo", "abc"]).await?, Value::from("abc"));
+
+    let info = client.hello(Hello::new().protover(4)).await?;
+    assert_eq!(info.proto, 3);
+
+    // AUTH takes a username and a password, a password on its own is a syntax error
+    match client.command(&["hello", "3", "auth", "secret"]).await? {
+        Value::Error(e) => assert!(e.starts_with("ERR syntax error"), "{}", e),
+        x => panic!("unexpected value: {:?}", x),
+    }
+
+    // The first value after AUTH is always the username, so this fails to authenticate
+    let res = client
+        .command(&["hello", "3", "auth", "secret", "setname", "x"])
+        .await;
+    assert!(!matches!(res, Ok(Value::Map(_))), "{:?}", res);
+    Ok(())
+}
+
+#[test]
+fn test_glob() {
+    assert!(glob_match("news.*", "news.tech"));
+    assert!(glob_match("h?llo", "hello"));
+    assert!(glob_match("h[ae]llo", "hallo"));
+    assert!(!glob_match("h[^e]llo", "hello"));
+    assert!(glob_match("h[a-c]llo", "hbllo"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("a\\*b", "a*b"));
+    assert!(!glob_match("a\\*b", "axb"));
+    assert!(!glob_match("news.*", "sport.news"));
+}
+
+#[tokio::test]
+async fn test_pubsub() -> Result<(), Error> {
+    use futures::StreamExt;
+
+    let addr = start_server().await;
+
+    // Confirmations are replies to the command, in the same shape as RESP2
+    let mut sub = Client::new(&addr, None).await?;
+    let res = sub.command(&["subscribe", "news"]).await?;
+    assert_eq!(res, array!["subscribe", "news", 1]);
+    sub.write(&array!["psubscribe", "sport.*"]).await?;
+    sub.flush().await?;
+    let res = sub.read().await?;
+    assert_eq!(res, array!["psubscribe", "sport.*", 2]);
+
+    // Messages without a `Subscription` are sent to the push stream
+    let mut pushes = sub.pushes().unwrap();
+    assert!(sub.pushes().is_none());
+
+    let mut publisher = Client::new(&addr, None).await?;
+    assert_eq!(
+        publisher.command(&["publish", "news", "hello"]).await?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        publisher
+            .command(&["publish", "sport.tennis", "ace"])
+            .await?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        publisher.command(&["pubsub", "numpat"]).await?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        publisher.command(&["pubsub", "channels"]).await?,
+        array!["news"]
+    );
+
+    assert_eq!(
+        pushes.next().await,
+        Some(Value::Push(
+            "message".into(),
+            vec!["news".into(), "hello".into()]
+        ))
+    );
+    assert_eq!(
+        pushes.next().await,
+        Some(Value::Push(
+            "pmessage".into(),
+            vec!["sport.*".into(), "sport.tennis".into(), "ace".into()]
+        ))
+    );
+    assert_eq!(sub.command(&["echo", "abc"]).await?, Value::from("abc"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_subscribe() -> Result<(), Error> {
+    use futures::StreamExt;
+
+    let addr = start_server().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    let mut news = client.subscribe(&["news"]).await?;
+    let mut sport = client.psubscribe(&["sport.*"]).await?;
+
+    let mut publisher = Client::new(&addr, None).await?;
+    publisher.command(&["publish", "news", "hello"]).await?;
+    publisher
+        .command(&["publish", "sport.tennis", "ace"])
+        .await?;
+
+    // Regular commands still work on the subscribed connection
+    assert_eq!(client.command(&["echo", "abc"]).await?, Value::from("abc"));
+
+    let msg = news.next().await.unwrap();
+    assert_eq!(msg.channel, "news");
+    assert_eq!(msg.pattern, None);
+    assert_eq!(msg.payload, Value::from("hello"));
+
+    let msg = sport.next().await.unwrap();
+    assert_eq!(msg.channel, "sport.tennis");
+    assert_eq!(msg.pattern.as_deref(), Some("sport.*"));
+
+    client.unsubscribe(Vec::<&str>::new()).await?;
+    assert_eq!(
+        publisher.command(&["publish", "news", "bye"]).await?,
+        Value::Int(0)
+    );
+
+    // Without subscriptions, RESP2 replies that look like messages are returned as arrays
+    let mut client = Client::connect(&addr, Hello::new().protover(2)).await?;
+    client.subscribe(&["news"]).await?;
+    client.unsubscribe(Vec::<&str>::new()).await?;
+    client.command(&["multi"]).await?;
+    for arg in &["message", "news", "x"] {
+        client.command(&["echo", arg]).await?;
+    }
+    assert_eq!(
+        client.command(&["exec"]).await?,
+        array!["message", "news", "x"]
+    );
+
+    // Messages that aren't read are dropped once the subscription's buffer is full
+    let server = Server::new(Echo);
+    let ctx = server.context();
+    let (listener, addr) = bind().await;
+    tokio::spawn(server.listener(listener).serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    let mut news = client.subscribe(&["news"]).await?;
+    let mut done = client.subscribe(&["done"]).await?;
+    for _ in 0..1100 {
+        ctx.broker().publish("news", "x");
+    }
+    ctx.broker().publish("done", "x");
+
+    // Messages are routed in order, so every `news` message has been handled by now
+    done.next().await.unwrap();
+    let mut n = 0;
+    let wait = std::time::Duration::from_millis(100);
+    while let Ok(Some(_)) = tokio::time::timeout(wait, news.next()).await {
+        n += 1;
+    }
+    assert_eq!(n, 1024);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_push() -> Result<(), Error> {
+    use futures::StreamExt;
+
+    let addr = start_server().await;
+
+    let mut a = Client::new(&addr, None).await?;
+    let mut b = Client::new(&addr, None).await?;
+    let id = a.server_info().unwrap().id.to_string();
+
+    // Pushes received while waiting for a reply never take the place of the reply
+    assert_eq!(b.command(&["notify", &id, "hi"]).await?, Value::Bool(true));
+    assert_eq!(b.command(&["notify", "0", "all"]).await?, Value::Int(2));
+    assert_eq!(
+        a.call(Command::new("echo").arg("x")).await?,
+        Value::from("x")
+    );
+    assert_eq!(
+        a.call(Command::new("echo").arg("y")).await?,
+        Value::from("y")
+    );
+    assert_eq!(
+        b.call(Command::new("echo").arg("z")).await?,
+        Value::from("z")
+    );
+
+    let mut pushes = a.pushes().unwrap();
+    assert_eq!(
+        pushes.next().await,
+        Some(Value::Push("notify".into(), vec!["hi".into()]))
+    );
+    assert_eq!(
+        pushes.next().await,
+        Some(Value::Push("notify".into(), vec!["all".into()]))
+    );
+    assert_eq!(
+        b.pushes().unwrap().next().await,
+        Some(Value::Push("notify".into(), vec!["all".into()]))
+    );
+
+    // The same applies to a subscribed connection, which is read by a background task
+    let _news = a.subscribe(&["news"]).await?;
+    assert_eq!(b.command(&["notify", &id, "hi2"]).await?, Value::Bool(true));
+    assert_eq!(
+        a.call(Command::new("echo").arg("w")).await?,
+        Value::from("w")
+    );
+    assert_eq!(
+        pushes.next().await,
+        Some(Value::Push("notify".into(), vec!["hi2".into()]))
+    );
+
+    assert_eq!(
+        b.command(&["notify", "999999", "hi"]).await?,
+        Value::Bool(false)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transaction() -> Result<(), Error> {
+    let addr = start_server().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    assert_eq!(client.command(&["multi"]).await?, Value::ok());
+    assert_eq!(client.command(&["echo", "a"]).await?, Value::from("QUEUED"));
+    assert_eq!(client.command(&["ping"]).await?, Value::from("QUEUED"));
+    assert_eq!(client.command(&["exec"]).await?, array!["a", "PONG"]);
+
+    // Unknown commands abort the transaction
+    client.command(&["multi"]).await?;
+    assert!(client.command(&["missing"]).await?.as_error().is_some());
+    let res = client.command(&["exec"]).await?;
+    assert!(res.as_error().unwrap().starts_with("EXECABORT"));
+
+    // AUTH and HELLO are rejected instead of running immediately
+    for command in &[&["auth", "secret"][..], &["hello", "3"][..]] {
+        client.command(&["multi"]).await?;
+        let res = client.command(command).await?;
+        assert!(res
+            .as_error()
+            .unwrap()
+            .contains("not allowed inside a transaction"));
+        let res = client.command(&["exec"]).await?;
+        assert!(res.as_error().unwrap().starts_with("EXECABORT"));
+    }
+
+    // Modifying a watched key aborts the transaction
+    let mut other = Client::new(&addr, None).await?;
+    client.command(&["watch", "key"]).await?;
+    client.command(&["multi"]).await?;
+    client.command(&["echo", "a"]).await?;
+    other.command(&["touch", "key"]).await?;
+    assert_eq!(client.command(&["exec"]).await?, Value::Null);
+
+    // Watches are cleared after EXEC
+    client.command(&["multi"]).await?;
+    client.command(&["echo", "b"]).await?;
+    other.command(&["touch", "key"]).await?;
+    assert_eq!(client.command(&["exec"]).await?, array!["b"]);
+
+    // Keys are watched in the selected database
+    client.command(&["watch", "key"]).await?;
+    other.command(&["select", "1"]).await?;
+    other.command(&["touch", "key"]).await?;
+    client.command(&["multi"]).await?;
+    client.command(&["echo", "c"]).await?;
+    assert_eq!(client.command(&["exec"]).await?, array!["c"]);
+
+    client.command(&["multi"]).await?;
+    assert_eq!(client.command(&["discard"]).await?, Value::ok());
+    assert!(client.command(&["exec"]).await?.as_error().is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_transaction() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let addr = start_server().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    let ((a, b), replies) = client
+        .transaction(|_client, tx| {
+            let a = tx.command::<String>(&["echo", "a"]);
+            let b = tx.command::<Value>(&["ping"]);
+            Box::pin(async move { Ok((a, b)) })
+        })
+        .await?;
+    assert_eq!(replies.get(a)?, "a");
+    assert_eq!(replies.get(b)?, Value::from("PONG"));
+
+    // The first attempt modifies the watched key, so it is retried
+    let attempts = std::sync::Arc::new(AtomicUsize::new(0));
+    let (_, replies) = client
+        .watch_transaction(&["key"], 1, |client, tx| {
+            let attempts = attempts.clone();
+            tx.command::<Value>(&["echo", "x"]);
+            Box::pin(async move {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    client.command(&["touch", "key"]).await?;
+                }
+                Ok(())
+            })
+        })
+        .await?;
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    assert_eq!(replies.into_inner(), vec![Value::from("x")]);
+
+    let res = client
+        .watch_transaction(&["key"], 0, |client, _tx| {
+            Box::pin(async move {
+                client.command(&["touch", "key"]).await?;
+                Ok(())
+            })
+        })
+        .await;
+    assert!(matches!(res, Err(Error::Aborted)));
+
+    // A failed MULTI is reported once every reply has been read
+    client
+        .command(&[
+            "acl", "setuser", "carol", "on", ">pw", "+@all", "-multi", "~*",
+        ])
+        .await?;
+    let mut carol = Client::new(&addr, Some(("carol", "pw"))).await?;
+    let res = carol
+        .watch_transaction(&["key"], 0, |_client, tx| {
+            tx.command::<Value>(&["echo", "x"]);
+            Box::pin(async move { Ok(()) })
+        })
+        .await;
+    match res {
+        Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::NoPerm),
+        x => panic!("unexpected result: {:?}", x.map(|_| ())),
+    }
+    assert_eq!(carol.command(&["echo", "abc"]).await?, Value::from("abc"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_buffered_value() -> Result<(), Error> {
+    let mut partial = Decoder::new(&b"*2\r\n$1\r\na\r\n$3\r\nab"[..]);
+    partial.readable().await?;
+    assert!(!partial.has_buffered_value());
+
+    let mut complete = Decoder::new(&b"*2\r\n$1\r\na\r\n%1\r\n+k\r\n:1\r\n*1"[..]);
+    complete.readable().await?;
+    assert!(complete.has_buffered_value());
+    complete.decode().await?;
+    assert!(!complete.has_buffered_value());
+
+    // Lengths that overflow are reported as buffered and rejected by `decode`
+    for input in &[
+        &b"*1\r\n$4\r\nPING\r\n%4611686018427387904\r\n"[..],
+        &b"*1\r\n$4\r\nPING\r\n|-4611686018427387905\r\n"[..],
+    ] {
+        let mut invalid = Decoder::new(*input);
+        invalid.decode().await?;
+        assert!(invalid.has_buffered_value());
+        assert!(invalid.decode().await.is_err());
+    }
+
+    // Truncated values with huge lengths fail without reserving memory for them
+    let mut large = Decoder::new(&b"*4611686018427387904\r\n:1\r\n"[..]);
+    assert!(large.decode().await.is_err());
+    let mut large = Decoder::new(&b"$9223372036854775807\r\nabc\r\n"[..]);
+    assert!(large.decode().await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipeline() -> Result<(), Error> {
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(Echo).max_batch(64).listener(listener).serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    for i in 0..500i64 {
+        client.write(&array!["echo", i.to_string()]).await?;
+    }
+    client.flush().await?;
+
+    for i in 0..500i64 {
+        assert_eq!(client.read().await?, Value::from(i.to_string()));
+    }
+
+    // A client that never reads its replies doesn't block other connections
+    let mut slow = Client::new(&addr, None).await?;
+    for _ in 0..64 {
+        slow.write(&array!["range", "100000"]).await?;
+    }
+    slow.flush().await?;
+    let echo = client.command(&["echo", "ok"]);
+    let res = tokio::time::timeout(std::time::Duration::from_secs(5), echo).await;
+    assert_eq!(res.expect("blocked by a slow client")?, Value::from("ok"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_response() -> Result<(), Error> {
+    let addr = start_server().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    assert_eq!(client.command(&["range", "3"]).await?, array![0, 1, 2]);
+    assert_eq!(
+        client.command(&["range", "2", "filter"]).await?,
+        array![0, 1]
+    );
+    assert_eq!(client.command(&["raw"]).await?, Value::from("raw"));
+
+    client.command(&["multi"]).await?;
+    client.command(&["range", "2"]).await?;
+    client.command(&["raw"]).await?;
+    assert_eq!(
+        client.command(&["exec"]).await?,
+        array![array![0, 1], "raw"]
+    );
+
+    let mut client = Client::connect(&addr, Hello::new().protover(2)).await?;
+    assert_eq!(client.command(&["range", "2"]).await?, array![0, 1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reply_error() -> Result<(), Error> {
+    let e = ReplyError::parse("MOVED 3999 127.0.0.1:6381");
+    assert_eq!(e.code, ErrorCode::Moved);
+    assert_eq!(e.redirect(), Some((3999, "127.0.0.1:6381")));
+    assert_eq!(ReplyError::parse("something failed").code, ErrorCode::Err);
+    assert_eq!(
+        ReplyError::parse("EXECABORT discarded").code,
+        ErrorCode::Custom("EXECABORT".into())
+    );
+
+    let value = Value::reply_error(ErrorCode::NoPerm, "no permissions");
+    assert_eq!(value, Value::error("NOPERM no permissions"));
+    assert_eq!(value.as_reply_error().unwrap().code, ErrorCode::NoPerm);
+    assert_eq!(Value::from(1).into_result(), Ok(Value::from(1)));
+
+    let addr = start_server().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    let s: String = client.call_as(Command::new("echo").arg("abc")).await?;
+    assert_eq!(s, "abc");
+
+    match client.call(Command::new("fail")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::WrongType),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    match client.call(Command::new("exec")).await {
+        Err(Error::Reply(e)) => {
+            assert_eq!(e.code, ErrorCode::Err);
+            assert_eq!(e.message, "EXEC without MULTI");
+        }
+        x => panic!("unexpected reply: {:?}", x),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_panic() -> Result<(), Error> {
+    let addr = start_server().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    match client.call(Command::new("panic")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "internal error"),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    // The connection and the handler are still usable
+    assert_eq!(client.command(&["echo", "abc"]).await?, Value::from("abc"));
+
+    // Including after a panic while producing a streamed reply
+    match client.call(Command::new("range").arg(2).arg("panic")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "internal error"),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+    assert_eq!(client.command(&["echo", "abc"]).await?, Value::from("abc"));
+
+    let res = client.exec(&array!["auth", 5, 6]).await?.into_result();
+    assert_eq!(res.unwrap_err().code, ErrorCode::Err);
+    assert_eq!(client.command(&["ping"]).await?, Value::from("PONG"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acl() -> Result<(), Error> {
+    let addr = start_server().await;
+
+    let mut admin = Client::new(&addr, None).await?;
+    assert_eq!(
+        admin.command(&["acl", "whoami"]).await?,
+        Value::from("default")
+    );
+    admin
+        .call(
+            Command::new("acl")
+                .arg("setuser")
+                .arg("alice")
+                .arg("on")
+                .arg(">secret")
+                .arg("~cache:*")
+                .arg("&news.*")
+                .arg("+@all")
+                .arg("-touch")
+                .arg("-@admin"),
+        )
+        .await?;
+
+    let res = admin
+        .call(Command::new("acl").arg("setuser").arg("bob").arg("bad"))
+        .await;
+    assert!(matches!(res, Err(Error::Reply(_))));
+    assert_eq!(
+        admin.command(&["acl", "getuser", "bob"]).await?,
+        Value::Null
+    );
+
+    // Invalid rules are rejected without affecting other commands
+    for rule in &["é", "", "+"] {
+        let res = admin
+            .call(Command::new("acl").arg("setuser").arg("bob").arg(*rule))
+            .await;
+        assert!(matches!(res, Err(Error::Reply(_))), "{:?}", rule);
+    }
+    assert_eq!(admin.command(&["ping"]).await?, Value::from("PONG"));
+
+    assert!(Client::new(&addr, Some(("alice", "wrong"))).await.is_err());
+
+    let mut alice = Client::new(&addr, Some(("alice", "secret"))).await?;
+    assert_eq!(
+        alice.command(&["acl", "whoami"]).await?,
+        Value::from("alice")
+    );
+    assert_eq!(alice.command(&["echo", "abc"]).await?, Value::from("abc"));
+    alice.call(Command::new("watch").arg("cache:1")).await?;
+    alice
+        .call(Command::new("publish").arg("news.tech").arg("hi"))
+        .await?;
+
+    let denied = [
+        Command::new("touch").arg("cache:1"),
+        Command::new("watch").arg("other"),
+        Command::new("publish").arg("sport").arg("hi"),
+        Command::new("acl").arg("list"),
+    ];
+    for command in denied.iter() {
+        match alice.call(command.clone()).await {
+            Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::NoPerm),
+            x => panic!("unexpected reply: {:?}", x),
+        }
+    }
+
+    let cat = admin.command(&["acl", "cat", "transaction"]).await?;
+    assert!(cat.as_array().unwrap().contains(&Value::from("multi")));
+
+    let user = admin.command(&["acl", "getuser", "alice"]).await?;
+    assert_eq!(
+        user.as_map().unwrap()[&Value::from("keys")],
+        array!["cache:*"]
+    );
+    assert_eq!(
+        admin.command(&["acl", "deluser", "alice"]).await?,
+        Value::from(1)
+    );
+
+    // Connections of a deleted user are closed and never fall back to the default user
+    if let Ok(Value::String(s)) = alice.command(&["echo", "abc"]).await {
+        panic!("deleted user executed a command: {}", s);
+    }
+    let acl = Acl::default();
+    match acl.check("alice", "echo", &[], &[], &[]) {
+        Err(e) => assert_eq!(e.code, ErrorCode::NoPerm),
+        x => panic!("unexpected result: {:?}", x),
+    }
+    assert!(admin
+        .command(&["acl", "deluser", "default"])
+        .await?
+        .as_error()
+        .is_some());
+    Ok(())
+}
+
+// Stand-in for an external credential store, lookups for `bob` wait until `release` is notified
+#[derive(Default)]
+struct Accounts {
+    started: tokio::sync::Notify,
+    release: tokio::sync::Notify,
+}
+
+#[async_trait]
+impl Authenticator for Accounts {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Principal, ReplyError> {
+        if username == "bob" {
+            self.started.notify_one();
+            self.release.notified().await;
+        }
+
+        match (username, password) {
+            ("bob", "secret") => Ok(Principal::new("bob").with_data(7u64)),
+            ("alice", "secret") => Ok(Principal::new("alice").with_data(42u64)),
+            _ => Err(ReplyError::new(
+                ErrorCode::Custom("WRONGPASS".into()),
+                "invalid username-password pair",
+            )),
+        }
+    }
+}
+
+#[derive(worm::Handler)]
+#[commands(account)]
+#[authenticator(accounts)]
+struct Secure {
+    accounts: std::sync::Arc<Accounts>,
+}
+
+impl Secure {
+    async fn account(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        _command: Command,
+    ) -> HandlerResult {
+        let id = client.principal().and_then(|p| p.data::<u64>()).copied();
+        Ok(Value::from(id.unwrap_or_default() as i64).into())
+    }
+}
+
+#[tokio::test]
+async fn test_authenticator() -> Result<(), Error> {
+    use futures::FutureExt;
+
+    let accounts = std::sync::Arc::new(Accounts::default());
+    let server = Secure {
+        accounts: accounts.clone(),
+    };
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(server).listener(listener).serve());
+
+    match Client::new(&addr, Some(("alice", "wrong"))).await {
+        Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::Custom("WRONGPASS".into())),
+        x => panic!("unexpected result: {:?}", x.map(|_| ())),
+    }
+
+    let mut client = Client::new(&addr, Some(("alice", "secret"))).await?;
+    assert_eq!(client.command(&["account"]).await?, Value::from(42));
+
+    // Other connections aren't blocked while a password is being verified
+    let mut slow = tokio::spawn(Client::new(addr.clone(), Some(("bob", "secret"))));
+    accounts.started.notified().await;
+    assert_eq!(client.command(&["account"]).await?, Value::from(42));
+    assert!((&mut slow).now_or_never().is_none());
+    accounts.release.notify_one();
+    let mut slow = slow.await.unwrap()?;
+    assert_eq!(slow.command(&["account"]).await?, Value::from(7));
+
+    // Services use the authenticator too
+    #[cfg(feature = "tower")]
+    {
+        use tower_service::Service;
+
+        let mut service = HandlerService::new(Secure { accounts });
+        let auth = Command::new("auth").arg("alice").arg("secret");
+        assert_eq!(service.call(auth).await?, Value::ok());
+        assert_eq!(
+            service.call(Command::new("account")).await?,
+            Value::from(42)
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_identity() -> Result<(), Error> {
+    let addr = start_server().await;
+
+    let mut client = Client::connect(&addr, Hello::new().setname("conn")).await?;
+    client
+        .command(&["acl", "setuser", "bob", "on", ">pw", "+@all", "~*", "&*"])
+        .await?;
+    let info = client.command(&["client", "info"]).await?;
+    assert!(info.as_string().unwrap().contains("name=conn user=default"));
+
+    assert_eq!(client.command(&["auth", "bob", "pw"]).await?, Value::ok());
+    assert_eq!(
+        client.command(&["acl", "whoami"]).await?,
+        Value::from("bob")
+    );
+    let info = client.command(&["client", "info"]).await?;
+    assert!(info.as_string().unwrap().contains("user=bob"));
+
+    // A failed re-authentication keeps the current user
+    assert!(client
+        .command(&["auth", "bob", "wrong"])
+        .await?
+        .as_error()
+        .is_some());
+    assert_eq!(
+        client.command(&["acl", "whoami"]).await?,
+        Value::from("bob")
+    );
+
+    client.command(&["subscribe", "news"]).await?;
+    assert_eq!(client.command(&["reset"]).await?, Value::from("RESET"));
+    assert_eq!(
+        client.command(&["acl", "whoami"]).await?,
+        Value::from("default")
+    );
+    assert_eq!(client.command(&["client", "getname"]).await?, Value::Null);
+    let info = client.command(&["client", "info"]).await?;
+    assert!(info.as_string().unwrap().contains("sub=0"));
+    assert!(info.as_string().unwrap().ends_with("resp=2"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config() -> Result<(), Error> {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = ServerConfig::new()
+        .max_clients(1)
+        .idle_timeout(Duration::from_millis(200))
+        .read_timeout(Duration::from_millis(100))
+        .command_timeout(Duration::from_millis(50))
+        .keepalive(Duration::from_secs(60));
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(Echo).config(config).listener(listener).serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    match Client::new(&addr, None).await {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "max number of clients reached"),
+        x => panic!("unexpected result: {:?}", x.map(|_| ())),
+    }
+
+    match client.call(Command::new("sleep").arg(500)).await {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "command timed out"),
+        x => panic!("unexpected result: {:?}", x),
+    }
+    match client
+        .call(Command::new("range").arg(2).arg("pending"))
+        .await
+    {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "command timed out"),
+        x => panic!("unexpected result: {:?}", x),
+    }
+    assert_eq!(client.command(&["echo", "abc"]).await?, Value::from("abc"));
+
+    // Idle connections are closed, the timeouts only bound how long the test waits for that
+    let closed = tokio::time::timeout(Duration::from_secs(5), client.read()).await;
+    assert!(closed.expect("connection should be closed").is_err());
+
+    // So are connections that stop in the middle of a command
+    let mut stream = tokio::net::TcpStream::connect(&addr).await?;
+    stream.write_all(b"*2\r\n$4\r\necho\r\n").await?;
+    let mut buf = Vec::new();
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+        .await
+        .expect("connection should be closed")?;
+    assert_eq!(n, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_builder() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let socket = std::env::temp_dir().join(format!("worm-test-{}.sock", std::process::id()));
+    let config = ServerConfig::parse(&format!(
+        r#"
+        # listeners
+        bind 127.0.0.1:6379
+        unixsocket {}
+        maxclients 10
+        timeout 5
+        command-timeout 500ms
+        requirepass "pass word"
+        user alice on >secret ~* &* +@all
+        "#,
+        socket.display()
+    ))?;
+    assert_eq!(config.bind.len(), 2);
+    assert_eq!(config.idle_timeout, Some(std::time::Duration::from_secs(5)));
+    assert_eq!(
+        config.command_timeout,
+        Some(std::time::Duration::from_millis(500))
+    );
+    assert!(ServerConfig::parse("unknown 1").is_err());
+    assert!(ServerConfig::parse("timeout 18446744073709551615m").is_err());
+    assert!(Server::builder(Echo)
+        .user("bob invalid-rule")
+        .bind("127.0.0.1:0")
+        .build()
+        .is_err());
+
+    // Servers that aren't created using the builder are checked when they start
+    for config in &[
+        ServerConfig::new().databases(0),
+        ServerConfig::new().protocol(4),
+    ] {
+        let (listener, _) = bind().await;
+        let server = Server::new(Echo).config(config.clone()).listener(listener);
+        assert!(matches!(server.serve().await, Err(Error::Config(_))));
+    }
+
+    // TCP connections use a listener bound to a free port instead of the configured address
+    let mut config = config;
+    config.bind.retain(|x| x.starts_with("unix:"));
+    let (listener, addr) = bind().await;
+
+    let connected = std::sync::Arc::new(AtomicUsize::new(0));
+    let count = connected.clone();
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let server = Server::builder(Echo)
+        .config(config)
+        .listener(listener)
+        .on_connect(move |_| {
+            count.fetch_add(1, Ordering::SeqCst);
+        })
+        .shutdown(async move {
+            let _ = rx.await;
+        })
+        .build()?;
+    let handle = tokio::spawn(server.serve());
+
+    assert!(Client::new(&addr, None).await.is_err());
+    let mut client = Client::new(&addr, Some(("default", "pass word"))).await?;
+    assert_eq!(client.command(&["echo", "tcp"]).await?, Value::from("tcp"));
+
+    // The Unix socket is bound once the server starts, by then the TCP listener has accepted
+    // connections so this can't fail for that reason
+    let mut client = Client::connect_unix(&socket, Hello::new().auth("alice", "secret")).await?;
+    assert_eq!(
+        client.command(&["acl", "whoami"]).await?,
+        Value::from("alice")
+    );
+    assert_eq!(connected.load(Ordering::SeqCst), 3);
+
+    tx.send(()).unwrap();
+    handle.await.unwrap()?;
+    assert!(Client::new(&addr, None).await.is_err());
+
+    // Files that aren't sockets aren't replaced
+    let path = std::env::temp_dir().join(format!("worm-test-{}.txt", std::process::id()));
+    std::fs::write(&path, "data")?;
+    let res = Server::builder(Echo)
+        .bind(format!("unix:{}", path.display()))
+        .build()?
+        .serve()
+        .await;
+    assert!(res.is_err());
+    assert_eq!(std::fs::read_to_string(&path)?, "data");
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+// Records every command name and rewrites `SHOUT x` into `ECHO X`
+struct Audit(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+#[async_trait]
+impl Middleware for Audit {
+    async fn call(&self, client: &mut Client, command: Command, next: Next<'_>) -> HandlerResult {
+        self.0.lock().unwrap().push(command.name().into());
+        if command.name() == "shout" {
+            let arg = command.args()[0]
+                .as_string()
+                .unwrap_or_default()
+                .to_uppercase();
+            return next.run(client, Command::new("echo").arg(arg)).await;
+        }
+        next.run(client, command).await
+    }
+}
+
+// Rejects `TOUCH` and replaces `ECHO` replies
+struct Guard;
+
+#[async_trait]
+impl Middleware for Guard {
+    async fn call(&self, client: &mut Client, command: Command, next: Next<'_>) -> HandlerResult {
+        match command.name() {
+            "touch" => Ok(ReplyError::new(ErrorCode::NoPerm, "touch is disabled").into()),
+            "echo" => match next.run(client, command).await?.into_value().await? {
+                Value::String(s) => Ok(Value::from(format!("<{}>", s)).into()),
+                x => Ok(x.into()),
+            },
+            _ => next.run(client, command).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_middleware() -> Result<(), Error> {
+    let log = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server = Server::new(Echo)
+        .middleware(Audit(log.clone()))
+        .middleware(Guard);
+    let (listener, addr) = bind().await;
+    tokio::spawn(server.listener(listener).serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    assert_eq!(
+        client.command(&["shout", "abc"]).await?,
+        Value::from("<ABC>")
+    );
+    assert_eq!(
+        client.command(&["echo", "abc"]).await?,
+        Value::from("<abc>")
+    );
+    match client.call(Command::new("touch").arg("key")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::NoPerm),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec!["hello", "shout", "echo", "touch"]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn test_tower() -> Result<(), Error> {
+    use tower_service::Service;
+
+    async fn call<S: Service<Command>>(
+        svc: &mut S,
+        command: Command,
+    ) -> Result<S::Response, S::Error> {
+        futures::future::poll_fn(|cx| svc.poll_ready(cx)).await?;
+        svc.call(command).await
+    }
+
+    let mut handler = HandlerService::new(Echo);
+    assert_eq!(
+        call(&mut handler, Command::new("echo").arg("abc")).await?,
+        Value::from("abc")
+    );
+    match call(&mut handler, Command::new("fail")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::WrongType),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::service(handler).listener(listener).serve());
+
+    let mut client = Client::new(&addr, None).await?.into_service();
+    assert_eq!(
+        call(&mut client, Command::new("echo").arg("abc")).await?,
+        Value::from("abc")
+    );
+    match call(&mut client, Command::new("fail")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.code, ErrorCode::WrongType),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    // Commands handled by the service can be queued
+    assert_eq!(call(&mut client, Command::new("multi")).await?, Value::ok());
+    assert_eq!(
+        call(&mut client, Command::new("echo").arg("abc")).await?,
+        Value::from("QUEUED")
+    );
+    assert_eq!(
+        call(&mut client, Command::new("exec")).await?,
+        Value::Array(vec![Value::from("abc")])
+    );
+
+    // A command waiting on the service doesn't block other connections
+    #[derive(Clone)]
+    struct Wait(std::sync::Arc<tokio::sync::Notify>);
+
+    impl Service<Command> for Wait {
+        type Response = Value;
+        type Error = Error;
+        type Future = futures::future::BoxFuture<'static, Result<Value, Error>>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, command: Command) -> Self::Future {
+            let started = self.0.clone();
+            Box::pin(async move {
+                if command.name() == "wait" {
+                    started.notify_one();
+                    futures::future::pending::<()>().await;
+                }
+                Ok(Value::ok())
+            })
+        }
+    }
+
+    let started = std::sync::Arc::new(tokio::sync::Notify::new());
+    let (listener, addr) = bind().await;
+    tokio::spawn(
+        Server::service(Wait(started.clone()))
+            .listener(listener)
+            .serve(),
+    );
+
+    let mut waiting = Client::new(&addr, None).await?;
+    waiting.write(&array!["wait"]).await?;
+    waiting.flush().await?;
+    started.notified().await;
+
+    let mut client = Client::new(&addr, None).await?;
+    let res =
+        tokio::time::timeout(std::time::Duration::from_secs(5), client.command(&["get"])).await;
+    assert_eq!(
+        res.expect("blocked by a pending service call")?,
+        Value::ok()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rate_limit() -> Result<(), Error> {
+    let config = ServerConfig::parse(
+        "ratelimit-client 10 20\nratelimit-user * 5 5\nratelimit-category @Dangerous 1 2\nratelimit-mode delay\nratelimit-error BUSY slow down",
+    )?;
+    assert_eq!(config.rate_limits.client, Some(Limit::new(10.0, 20)));
+    assert_eq!(config.rate_limits.users["*"], Limit::new(5.0, 5));
+    assert_eq!(
+        config.rate_limits.categories["dangerous"],
+        Limit::new(1.0, 2)
+    );
+    assert!(config.rate_limits.delay);
+    assert_eq!(config.rate_limits.error.code, ErrorCode::Busy);
+    assert!(ServerConfig::parse("ratelimit-client 0 1").is_err());
+
+    // The `HELLO` sent when connecting uses one of the tokens
+    let (listener, addr) = bind().await;
+    let server = Server::builder(Echo)
+        .listener(listener)
+        .rate_limits(RateLimits::new().client(Limit::new(1.0, 3)))
+        .build()?;
+    tokio::spawn(server.serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    assert_eq!(client.command(&["ping"]).await?, Value::from("PONG"));
+    assert_eq!(client.command(&["ping"]).await?, Value::from("PONG"));
+    match client.call(Command::new("ping")).await {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "rate limit exceeded"),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+
+    let mut admin = Client::new(&addr, None).await?;
+    let info = admin.call(Command::new("ratelimit").arg("info")).await?;
+    let clients = match &info {
+        Value::Map(m) => &m[&Value::from("clients")],
+        x => panic!("unexpected reply: {:?}", x),
+    };
+    match clients {
+        Value::Map(m) => assert_eq!(m.len(), 2),
+        x => panic!("unexpected reply: {:?}", x),
+    }
+    assert_eq!(
+        admin.call(Command::new("ratelimit").arg("reset")).await?,
+        Value::ok()
+    );
+    assert_eq!(client.command(&["ping"]).await?, Value::from("PONG"));
+
+    // In delay mode commands wait for the bucket to refill
+    let (listener, addr) = bind().await;
+    let server = Server::builder(Echo)
+        .listener(listener)
+        .rate_limits(
+            RateLimits::new()
+                .category("connection", Limit::new(20.0, 1))
+                .delay(true),
+        )
+        .build()?;
+    tokio::spawn(server.serve());
+
+    let mut client = Client::new(&addr, None).await?;
+
+    // Timers fire as soon as the runtime is idle once the clock is paused, the elapsed time
+    // only includes the delays
+    tokio::time::pause();
+    let start = tokio::time::Instant::now();
+    for _ in 0..3 {
+        assert_eq!(client.command(&["ping"]).await?, Value::from("PONG"));
+    }
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_info() -> Result<(), Error> {
+    let (listener, addr) = bind().await;
+    let server = Server::builder(Echo)
+        .listener(listener)
+        .max_clients(2)
+        .build()?;
+    tokio::spawn(server.serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    let _other = Client::new(&addr, None).await?;
+    assert!(Client::new(&addr, None).await.is_err());
+
+    client.command(&["echo", "a"]).await?;
+    client.command(&["echo", "b"]).await?;
+    assert!(client.call(Command::new("fail")).await.is_err());
+
+    let info = Info::parse(&client.call_as::<String>(Command::new("info")).await?);
+    assert_eq!(
+        info.get("server", "worm_version"),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+    assert_eq!(info.get("clients", "connected_clients"), Some("2"));
+    assert_eq!(info.get("stats", "rejected_connections"), Some("1"));
+    assert_eq!(info.get("echo", "answer"), Some("42"));
+    assert!(
+        info.get("stats", "total_net_input_bytes")
+            .unwrap()
+            .parse::<u64>()?
+            > 0
+    );
+    assert!(!info.sections().any(|x| x == "Commandstats"));
+
+    let info = Info::parse(
+        &client
+            .call_as::<String>(Command::new("info").arg("commandstats"))
+            .await?,
+    );
+    assert_eq!(info.sections().collect::<Vec<_>>(), vec!["Commandstats"]);
+    assert!(info
+        .get("commandstats", "cmdstat_echo")
+        .unwrap()
+        .starts_with("calls=2,"));
+    assert!(info
+        .get("commandstats", "cmdstat_fail")
+        .unwrap()
+        .ends_with("failed_calls=1"));
+
+    // RESP3 replies are verbatim strings, RESP2 replies are bulk strings
+    for (protover, prefix) in &[(3, b'='), (2, b'$')] {
+        client.hello(Hello::new().protover(*protover)).await?;
+        client
+            .output
+            .encode(&Value::Array(vec!["info".into(), "server".into()]))
+            .await?;
+        client.flush().await?;
+        let input = client.input().unwrap();
+        assert_eq!(input.next_prefix().await?, *prefix);
+        let text = match prefix {
+            b'=' => input.read_verbatim_string().await?,
+            _ => input.read_blob_string().await?,
+        };
+        assert!(text.as_string().unwrap().starts_with("# Server\r\n"));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_registry() -> Result<(), Error> {
+    use futures::FutureExt;
+
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(Echo).listener(listener).serve());
+
+    let mut admin = Client::new(&addr, None).await?;
+    admin.command(&["client", "setname", "admin"]).await?;
+    let mut a = Client::new(&addr, None).await?;
+    a.command(&["echo", "abc"]).await?;
+
+    let list = admin
+        .call_as::<String>(Command::new("client").arg("list"))
+        .await?;
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("name=admin user=default"));
+    assert!(lines[0].contains("cmd=client"));
+    assert!(lines[1].contains("cmd=echo"));
+    assert!(lines[1].contains("idle=0"));
+
+    let id = a.call_as::<i64>(Command::new("client").arg("id")).await?;
+    let list = admin
+        .call_as::<String>(Command::new("client").arg("list").arg("id").arg(id))
+        .await?;
+    assert!(list.starts_with(&format!("id={} ", id)));
+    assert_eq!(list.lines().count(), 1);
+
+    // KILL closes the connection, the admin connection is skipped by default
+    let n = admin
+        .call_as::<i64>(
+            Command::new("client")
+                .arg("kill")
+                .arg("user")
+                .arg("default"),
+        )
+        .await?;
+    assert_eq!(n, 1);
+    while !admin
+        .call_as::<String>(Command::new("client").arg("list").arg("id").arg(id))
+        .await?
+        .is_empty()
+    {}
+    assert!(a.command(&["echo", "abc"]).await.is_err());
+
+    let mut b = Client::new(&addr, None).await?;
+    let info = b
+        .call_as::<String>(Command::new("client").arg("info"))
+        .await?;
+    let peer = info
+        .split(' ')
+        .find_map(|x| x.strip_prefix("addr="))
+        .unwrap()
+        .to_string();
+    let res = admin
+        .call(Command::new("client").arg("kill").arg(peer.as_str()))
+        .await?;
+    assert_eq!(res, Value::ok());
+    assert!(admin
+        .call(Command::new("client").arg("kill").arg(peer.as_str()))
+        .await
+        .is_err());
+
+    // Commands wait while clients are paused, other than `CLIENT` commands
+    let mut c = Client::new(&addr, None).await?;
+    let id = c.server_info().unwrap().id;
+    let input = |list: String| {
+        list.split(' ')
+            .find_map(|x| x.strip_prefix("tot-net-in=").map(String::from))
+    };
+    let client_list = Command::new("client").arg("list").arg("id").arg(id as i64);
+    let before = input(admin.call_as::<String>(client_list.clone()).await?);
+    admin.command(&["client", "pause", "10000"]).await?;
+    let mut task = tokio::spawn(async move { c.command(&["echo", "abc"]).await });
+
+    // Once the server has read the command it waits for the pause to end
+    while input(admin.call_as::<String>(client_list.clone()).await?) == before {}
+    assert!((&mut task).now_or_never().is_none());
+    admin.command(&["client", "unpause"]).await?;
+    assert_eq!(task.await.unwrap()?, Value::from("abc"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_monitor() -> Result<(), Error> {
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(Echo).listener(listener).serve());
+
+    let mut monitor = Client::new(&addr, None).await?;
+    assert_eq!(monitor.command(&["monitor"]).await?, Value::ok());
+
+    let mut client = Client::new(&addr, None).await?;
+    client.command(&["echo", "a \"b\"\r\n"]).await?;
+    let _ = client.command(&["auth", "alice", "secret"]).await;
+
+    let line = match monitor.read().await? {
+        Value::String(s) => s,
+        x => panic!("unexpected value: {:?}", x),
+    };
+    assert!(line.ends_with(r#"] "hello" "3""#), "{}", line);
+
+    let line = monitor.read().await?;
+    let line = line.as_string().unwrap();
+    assert!(line.contains(" [0 127.0.0.1:"));
+    assert!(line.ends_with(r#"] "echo" "a \"b\"\r\n""#), "{}", line);
+
+    let line = monitor.read().await?;
+    let line = line.as_string().unwrap();
+    assert!(
+        line.ends_with(r#"] "auth" "(redacted)" "(redacted)""#),
+        "{}",
+        line
+    );
+    assert!(!line.contains("secret"));
+
+    // RESET stops monitoring, its own command is the last line sent
+    assert_eq!(monitor.command(&["reset"]).await?, Value::from("RESET"));
+    let line = monitor.read().await?;
+    assert!(line.as_string().unwrap().ends_with(r#"] "reset""#));
+    client.command(&["echo", "abc"]).await?;
+    assert_eq!(monitor.command(&["ping"]).await?, Value::from("PONG"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_slowlog() -> Result<(), Error> {
+    let config = ServerConfig::parse("slowlog-log-slower-than -1\nslowlog-max-len 2")?;
+    assert_eq!(config.slowlog_threshold, None);
+    assert_eq!(config.slowlog_max_len, 2);
+
+    let (listener, addr) = bind().await;
+    let server = Server::builder(Echo)
+        .listener(listener)
+        .slowlog_threshold(Some(std::time::Duration::from_millis(20)))
+        .slowlog_max_len(3)
+        .build()?;
+    tokio::spawn(server.serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    client.command(&["client", "setname", "slow"]).await?;
+    client.command(&["echo", "fast"]).await?;
+    assert_eq!(client.command(&["slowlog", "len"]).await?, Value::from(0));
+
+    client.command(&["sleep", "30"]).await?;
+    let long = "x".repeat(200);
+    client.command(&["sleep", "30", &long]).await?;
+    let args: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+    let mut cmd = vec!["sleep", "30"];
+    cmd.extend(args.iter().map(|x| x.as_str()));
+    client.command(&cmd).await?;
+    client.command(&["sleep", "30"]).await?;
+    assert_eq!(client.command(&["slowlog", "len"]).await?, Value::from(3));
+
+    let entries = client.command(&["slowlog", "get", "-1"]).await?;
+    let entries = match entries {
+        Value::Array(x) => x,
+        x => panic!("unexpected value: {:?}", x),
+    };
+    assert_eq!(entries.len(), 3);
+    let entry = match &entries[0] {
+        Value::Array(x) => x,
+        x => panic!("unexpected value: {:?}", x),
+    };
+    assert_eq!(entry.len(), 6);
+    assert!(entry[2].as_int().unwrap() >= 20_000);
+    assert_eq!(entry[3], Value::Array(vec!["sleep".into(), "30".into()]));
+    assert!(entry[4].as_string().unwrap().starts_with("127.0.0.1:"));
+    assert_eq!(entry[5], Value::from("slow"));
+
+    // Long argument lists are truncated to 32 values
+    let entry = match &entries[1] {
+        Value::Array(x) => x,
+        x => panic!("unexpected value: {:?}", x),
+    };
+    let args = match &entry[3] {
+        Value::Array(x) => x,
+        x => panic!("unexpected value: {:?}", x),
+    };
+    assert_eq!(args.len(), 32);
+    assert_eq!(args[31], Value::from("... (11 more arguments)"));
+
+    // The oldest entry has been removed, long arguments are truncated to 128 bytes
+    let entry = match &entries[2] {
+        Value::Array(x) => x,
+        x => panic!("unexpected value: {:?}", x),
+    };
+    assert_eq!(entry[0], Value::from(1));
+    assert_eq!(
+        entry[3],
+        Value::Array(vec![
+            "sleep".into(),
+            "30".into(),
+            format!("{}... (72 more bytes)", "x".repeat(128)).into()
+        ])
+    );
+
+    match client.command(&["slowlog", "get", "1"]).await? {
+        Value::Array(x) => assert_eq!(x.len(), 1),
+        x => panic!("unexpected value: {:?}", x),
+    }
+    assert_eq!(client.command(&["slowlog", "reset"]).await?, Value::ok());
+    assert_eq!(client.command(&["slowlog", "len"]).await?, Value::from(0));
+    assert!(client
+        .call(Command::new("slowlog").arg("nope"))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_push_buffer() -> Result<(), Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = Server::new(Echo).config(ServerConfig::new().push_buffer(4));
+    let ctx = server.context();
+    let (listener, addr) = bind().await;
+    tokio::spawn(server.listener(listener).serve());
+
+    let mut raw = tokio::net::TcpStream::connect(&addr).await?;
+    raw.write_all(b"*2\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n")
+        .await?;
+    let mut buf = [0; 4];
+    raw.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b">3\r\n");
+
+    // The connection isn't able to write anything while the messages are published, once its
+    // queue is full messages are dropped and it's disconnected
+    let received: Vec<usize> = (0..8).map(|_| ctx.broker().publish("news", "x")).collect();
+    assert_eq!(received, vec![1, 1, 1, 1, 0, 0, 0, 0]);
+
+    let mut rest = Vec::new();
+    raw.read_to_end(&mut rest).await?;
+    assert!(!rest.windows(7).any(|x| x == b"message"));
+    assert_eq!(ctx.broker().publish("news", "x"), 0);
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_metrics() -> Result<(), Error> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (listener, addr) = bind().await;
+    let (metrics_listener, metrics_addr) = bind().await;
+    let server = Server::builder(Echo)
+        .listener(listener)
+        .metrics_listener(metrics_listener)
+        .build()?;
+    let ctx = server.context();
+    tokio::spawn(server.serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    client.command(&["echo", "abc"]).await?;
+    assert!(client.call(Command::new("fail")).await.is_err());
+    assert!(client
+        .call(Command::new("auth").arg("alice").arg("secret"))
+        .await
+        .is_err());
+
+    // Values other than commands are protocol errors
+    let mut raw = tokio::net::TcpStream::connect(&addr).await?;
+    raw.write_all(b":1\r\n*1\r\n$4\r\nping\r\n").await?;
+    let mut buf = [0; 7];
+    raw.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"+PONG\r\n");
+
+    let metrics = ctx.metrics();
+    assert_eq!(metrics.commands["echo"].calls, 1);
+    assert_eq!(metrics.commands["echo"].latency.count, 1);
+    assert_eq!(metrics.commands["fail"].failed, 1);
+    assert_eq!(metrics.auth_failures, 1);
+    assert_eq!(metrics.protocol_errors, 1);
+    assert_eq!(metrics.connections, 2);
+    assert!(metrics.bytes_read > 0 && metrics.bytes_written > 0);
+
+    let metrics = client.metrics();
+    assert_eq!(metrics.commands["echo"].calls, 1);
+    assert_eq!(metrics.commands["fail"].failed, 1);
+    assert_eq!(metrics.auth_failures, 1);
+    assert!(metrics.bytes_read > 0 && metrics.bytes_written > 0);
+
+    let get = |path: &'static str| {
+        let addr = metrics_addr.clone();
+        async move {
+            let mut http = tokio::net::TcpStream::connect(addr).await?;
+            http.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+                .await?;
+            let mut res = String::new();
+            http.read_to_string(&mut res).await?;
+            Ok::<_, Error>(res)
+        }
+    };
+
+    let res = get("/metrics").await?;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.contains("\nworm_commands_total{command=\"echo\"} 1\n"));
+    assert!(res.contains("\nworm_command_duration_seconds_count{command=\"echo\"} 1\n"));
+    assert!(res.contains("\nworm_connected_clients 2\n"));
+    assert!(res.contains("\nworm_auth_failures_total 1\n"));
+    assert!(get("/").await?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    Ok(())
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_tracing() -> Result<(), Error> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+
+    // Records the name and fields of every span and event as a line
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<String>>>, Arc<AtomicU64>);
+
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl tracing::Subscriber for Lines {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut line = span.metadata().name().to_string();
+            span.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+            Id::from_u64(self.1.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            let mut line = "record".to_string();
+            values.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut line = "event".to_string();
+            event.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    // The test runtime is single threaded, so the server tasks use the same subscriber
+    let lines = Lines::default();
+    let _guard = tracing::subscriber::set_default(lines.clone());
+
+    let (listener, addr) = bind().await;
+    tokio::spawn(Server::new(Echo).listener(listener).serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    client.command(&["echo", "abc"]).await?;
+    let _ = client.command(&["auth", "alice", "secret"]).await?;
+    let _ = client
+        .command(&["hello", "3", "auth", "bob", "hunter2"])
+        .await?;
+
+    let lines = lines.0.lock().unwrap().clone();
+    assert!(lines
+        .iter()
+        .any(|x| x.starts_with("connection peer=127.0.0.1:") && x.contains(" client_id=")));
+    assert!(lines.iter().any(|x| x == "command name=\"echo\""));
+    assert!(lines.iter().any(|x| x.starts_with("record duration_us=")));
+    assert!(lines.iter().any(|x| x == "record failed=true"));
+    assert!(lines
+        .iter()
+        .any(|x| x.starts_with("event args=") && x.contains("(redacted)")));
+    assert!(!lines
+        .iter()
+        .any(|x| x.contains("secret") || x.contains("hunter2")));
+    Ok(())
+}
+
+#[derive(Default, worm::Handler)]
+#[commands(db, r#move)]
+struct Databases;
+
+impl Databases {
+    async fn db(&mut self, client: std::pin::Pin<&mut Client>, _command: Command) -> HandlerResult {
+        Ok(Value::from(client.db() as i64).into())
+    }
+
+    async fn r#move(
+        &mut self,
+        client: std::pin::Pin<&mut Client>,
+        command: Command,
+    ) -> HandlerResult {
+        let ctx = client.context().cloned().unwrap_or_default();
+        match ctx.db_index(&command.args()[1]) {
+            Ok(db) if db != client.db() => Ok(Value::from(1).into()),
+            Ok(_) => Ok(Value::from(0).into()),
+            Err(e) => Ok(e.into()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_select() -> Result<(), Error> {
+    assert_eq!(ServerConfig::parse("databases 4")?.databases, 4);
+    assert!(ServerConfig::parse("databases 0").is_err());
+    assert!(Server::builder(Databases)
+        .bind("127.0.0.1:0")
+        .databases(0)
+        .build()
+        .is_err());
+
+    let (listener, addr) = bind().await;
+    let server = Server::builder(Databases)
+        .listener(listener)
+        .databases(4)
+        .build()?;
+    tokio::spawn(server.serve());
+
+    let mut client = Client::new(&addr, None).await?;
+    assert_eq!(client.db(), 0);
+    client.select(2).await?;
+    assert_eq!(client.db(), 2);
+    assert_eq!(client.command(&["db"]).await?, Value::from(2));
+    let info = client.command(&["client", "info"]).await?;
+    assert!(info.as_string().unwrap().contains(" db=2 "));
+
+    // Raw identifiers are dispatched without the `r#` prefix
+    assert!(client
+        .command(&["commands"])
+        .await?
+        .into_result()?
+        .as_array()
+        .unwrap()
+        .contains(&Value::from("move")));
+    assert_eq!(client.command(&["move", "a", "3"]).await?, Value::from(1));
+
+    match client.select(4).await {
+        Err(Error::Reply(e)) => assert_eq!(e.message, "DB index is out of range"),
+        x => panic!("unexpected result: {:?}", x),
+    }
+    assert!(client.call(Command::new("select").arg("x")).await.is_err());
+    match client.command(&["move", "a", "-1"]).await? {
+        Value::Error(e) => assert!(e.contains("out of range")),
+        x => panic!("unexpected value: {:?}", x),
+    }
+    assert_eq!(client.db(), 2);
+
+    // The selected database is applied when a transaction is executed
+    client.command(&["multi"]).await?;
+    client.command(&["select", "1"]).await?;
+    client.command(&["db"]).await?;
+    assert_eq!(
+        client.command(&["exec"]).await?,
+        Value::Array(vec![Value::ok(), Value::from(1)])
+    );
+
+    assert_eq!(client.command(&["reset"]).await?, Value::from("RESET"));
+    assert_eq!(client.command(&["db"]).await?, Value::from(0));
     Ok(())
 }
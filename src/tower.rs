@@ -0,0 +1,212 @@
+//! Adapters between `Handler`, `Client` and `tower_service::Service`, enabled using the `tower`
+//! feature
+
+use crate::internal::*;
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+use tower_service::Service;
+
+/// `Service` executing commands using a `Handler` in the current process
+///
+/// Each service has its own connection state, so `AUTH`, `MULTI` and `CLIENT SETNAME` apply to
+/// the service they were sent to. Clones share the handler but start with a new connection.
+/// Error replies are returned as `Error::Reply`
+///
+/// Commands are executed without the middleware registered using `Server::middleware`, tower
+/// layers can be used instead
+pub struct HandlerService<T> {
+    handler: Arc<Mutex<T>>,
+    client: Arc<Mutex<Client>>,
+    context: ServerContext,
+}
+
+impl<T: Handler> HandlerService<T> {
+    pub fn new(handler: T) -> HandlerService<T> {
+        HandlerService::with_context(handler, ServerContext::default())
+    }
+
+    /// Create a service using the ACL and pubsub broker from an existing context, for example
+    /// `Server::context`
+    pub fn with_context(handler: T, context: ServerContext) -> HandlerService<T> {
+        HandlerService {
+            handler: Arc::new(Mutex::new(handler)),
+            client: Arc::new(Mutex::new(Client::local(context.clone()))),
+            context,
+        }
+    }
+}
+
+impl<T> Clone for HandlerService<T> {
+    fn clone(&self) -> HandlerService<T> {
+        HandlerService {
+            handler: self.handler.clone(),
+            client: Arc::new(Mutex::new(Client::local(self.context.clone()))),
+            context: self.context.clone(),
+        }
+    }
+}
+
+impl<T: 'static + Handler> Service<Command> for HandlerService<T> {
+    type Response = Value;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Value, Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, command: Command) -> Self::Future {
+        let handler = self.handler.clone();
+        let client = self.client.clone();
+        Box::pin(async move {
+            let mut client = client.lock().await;
+
+            // The authenticator runs without holding the handler lock, like it does in the server
+            let authenticator = match auth::credentials(&command) {
+                Some(creds) if client.multi.is_none() => {
+                    handler.lock().await.authenticator().map(|x| (x, creds))
+                }
+                _ => None,
+            };
+            if let Some((authenticator, (username, password))) = authenticator {
+                let addr = client.addrs()[0];
+                let res = server::verify(&*authenticator, username, password, addr).await;
+                client.verified = Some(res);
+            }
+
+            // Deferred replies are awaited after releasing the handler lock
+            let res = handler.lock().await.execute(&mut client, command).await;
+            client.verified = None;
+            let value = match res {
+                Ok(x) => x.into_value().await?,
+                Err(e) => server::error_reply(e),
+            };
+            Ok(value.into_result()?)
+        })
+    }
+}
+
+/// `Handler` forwarding every command to a `Service`, use `Server::service` to serve it
+///
+/// Built-in commands are still handled by the server. Errors returned by the service are sent to
+/// the client as error replies
+///
+/// Each command is sent to a clone of the service, which is driven without holding the handler
+/// lock, so a slow service or one that isn't ready doesn't block other connections. Services
+/// that can't be cloned cheaply can be wrapped in `tower::buffer::Buffer`. Commands queued by
+/// `MULTI` are still executed one at a time, while holding the lock
+pub struct ServiceHandler<S> {
+    service: S,
+}
+
+impl<S> ServiceHandler<S> {
+    pub fn new(service: S) -> ServiceHandler<S> {
+        ServiceHandler { service }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.service
+    }
+}
+
+// Keep the reply code of errors returned by `Client` and `HandlerService`
+fn service_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> anyhow::Error {
+    let e = e.into();
+    let e = match e.downcast::<Error>() {
+        Ok(e) => return (*e).into(),
+        Err(e) => e,
+    };
+    match e.downcast::<ReplyError>() {
+        Ok(e) => (*e).into(),
+        Err(e) => anyhow::anyhow!(e),
+    }
+}
+
+impl<S> Handler for ServiceHandler<S>
+where
+    S: 'static + Service<Command, Response = Value> + Clone + Send,
+    S::Future: Send,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    fn dispatch<'a>(
+        &'a mut self,
+        _client: std::pin::Pin<&'a mut Client>,
+        command: Command,
+    ) -> std::pin::Pin<Box<dyn 'a + Send + std::future::Future<Output = HandlerResult>>> {
+        let mut service = self.service.clone();
+        Box::pin(async move {
+            Ok(Response::deferred(async move {
+                futures::future::poll_fn(|cx| service.poll_ready(cx))
+                    .await
+                    .map_err(service_error)?;
+                let value = service.call(command).await.map_err(service_error)?;
+                Ok(value.into())
+            }))
+        })
+    }
+
+    fn commands(&self) -> &[&str] {
+        &[]
+    }
+
+    // Any command that isn't built in is sent to the service
+    fn has_command(&self, _command: &str) -> bool {
+        true
+    }
+
+    fn password_required(&self) -> bool {
+        false
+    }
+
+    fn _check_password(&self, _username: &str, _password: &str) -> bool {
+        true
+    }
+}
+
+impl<S> Server<ServiceHandler<S>>
+where
+    S: 'static + Service<Command, Response = Value> + Clone + Send,
+    S::Future: Send,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    /// Create a server executing commands using `service`
+    pub fn service(service: S) -> Self {
+        Server::new(ServiceHandler::new(service))
+    }
+}
+
+/// `Service` sending commands to a server using a shared `Client`, created using
+/// `Client::into_service`
+///
+/// Commands are sent one at a time, error replies are returned as `Error::Reply`
+#[derive(Clone)]
+pub struct ClientService {
+    client: Arc<Mutex<Client>>,
+}
+
+impl Client {
+    pub fn into_service(self) -> ClientService {
+        ClientService {
+            client: Arc::new(Mutex::new(self)),
+        }
+    }
+}
+
+impl Service<Command> for ClientService {
+    type Response = Value;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Value, Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, command: Command) -> Self::Future {
+        let client = self.client.clone();
+        Box::pin(async move { client.lock().await.call(command).await })
+    }
+}
@@ -0,0 +1,145 @@
+use crate::internal::*;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Commands queued between `MULTI` and `EXEC`
+#[derive(Default)]
+pub(crate) struct MultiState {
+    pub(crate) commands: Vec<Command>,
+
+    // Set when a command could not be queued, `EXEC` will then discard the transaction
+    pub(crate) error: bool,
+}
+
+pub(crate) const COMMANDS: &[&str] = &["multi", "exec", "discard", "watch", "unwatch"];
+
+// Commands that can't be executed as part of a transaction
+pub(crate) const NOT_ALLOWED: &[&str] = &[
+    "monitor",
+    "hello",
+    "auth",
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+];
+
+// Dirty flag of each connection watching a key, by client ID
+type KeyWatchers = BTreeMap<u64, Arc<AtomicBool>>;
+
+/// Connections watching each key, indexed by database and key. Modifying a key marks all of its
+/// watchers as dirty
+#[derive(Default)]
+pub(crate) struct Watchers {
+    keys: std::sync::Mutex<BTreeMap<(usize, Value), KeyWatchers>>,
+}
+
+impl Watchers {
+    pub(crate) fn watch(&self, id: u64, dirty: &Arc<AtomicBool>, key: (usize, Value)) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.entry(key).or_default().insert(id, dirty.clone());
+    }
+
+    pub(crate) fn unwatch(&self, id: u64, watched: &[(usize, Value)]) {
+        let mut keys = self.keys.lock().unwrap();
+        for key in watched {
+            if let Some(w) = keys.get_mut(key) {
+                w.remove(&id);
+                if w.is_empty() {
+                    keys.remove(key);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn touch(&self, db: usize, key: &Value) {
+        let keys = self.keys.lock().unwrap();
+        if let Some(w) = keys.get(&(db, key.clone())) {
+            for dirty in w.values() {
+                dirty.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Handle to the reply of a command queued in a `Transaction`
+pub struct Queued<T> {
+    index: usize,
+    _t: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Queued<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Queued<T> {}
+
+impl<T> Queued<T> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// Commands to be executed atomically by `Client::transaction`
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl Transaction {
+    /// Queue a command, the returned handle is used to get the typed reply from `Replies`
+    pub fn add<T>(&mut self, command: Command) -> Queued<T> {
+        self.commands.push(command);
+        Queued {
+            index: self.commands.len() - 1,
+            _t: std::marker::PhantomData,
+        }
+    }
+
+    pub fn command<'a, T>(&mut self, args: impl AsRef<[&'a str]>) -> Queued<T> {
+        let args = args.as_ref();
+        let mut cmd = Command::new(args.first().copied().unwrap_or_default());
+        for arg in args.iter().skip(1) {
+            cmd = cmd.arg(*arg);
+        }
+        self.add(cmd)
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// Replies returned by `EXEC`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replies(pub Vec<Value>);
+
+impl Replies {
+    pub fn value<T>(&self, queued: Queued<T>) -> &Value {
+        &self.0[queued.index]
+    }
+
+    /// Convert the reply for a queued command, error replies are returned as `Error::Reply`
+    pub fn get<T>(&self, queued: Queued<T>) -> Result<T, Error>
+    where
+        T: std::convert::TryFrom<Value>,
+        Error: From<T::Error>,
+    {
+        match self.value(queued) {
+            Value::Error(e) => Err(ReplyError::parse(e).into()),
+            x => Ok(T::try_from(x.clone())?),
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<Value> {
+        self.0
+    }
+}
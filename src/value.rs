@@ -25,14 +25,14 @@ impl Value {
         x.into()
     }
 
-    pub fn done() -> Result<Value, anyhow::Error> {
-        Err(Error::Done.into())
-    }
-
     pub fn error(x: impl Into<String>) -> Value {
         Value::Error(x.into())
     }
 
+    pub fn reply_error(code: ErrorCode, message: impl Into<String>) -> Value {
+        ReplyError::new(code, message).into()
+    }
+
     pub fn ok() -> Value {
         Value::String("OK".into())
     }
@@ -72,10 +72,7 @@ impl Value {
         match self {
             Value::Int(x) => Some(*x),
             Value::Float(f) => Some(f.into_inner() as i64),
-            Value::String(s) => match s.parse() {
-                Ok(x) => Some(x),
-                Err(_) => None,
-            },
+            Value::String(s) => s.parse().ok(),
             _ => None,
         }
     }
@@ -94,12 +91,9 @@ impl Value {
         }
 
         match self {
-            Value::Float(x) => Some(x.clone().into()),
+            Value::Float(x) => Some((*x).into()),
             Value::Int(x) => Some(*x as f64),
-            Value::String(s) => match s.parse() {
-                Ok(x) => Some(x),
-                Err(_) => None,
-            },
+            Value::String(s) => s.parse().ok(),
             _ => None,
         }
     }
@@ -120,6 +114,19 @@ impl Value {
         None
     }
 
+    /// Parse an error reply into its code and message
+    pub fn as_reply_error(&self) -> Option<ReplyError> {
+        self.as_error().map(ReplyError::parse)
+    }
+
+    /// Convert error replies into `Err`, any other value is returned unchanged
+    pub fn into_result(self) -> Result<Value, ReplyError> {
+        match self {
+            Value::Error(e) => Err(ReplyError::parse(&e)),
+            x => Ok(x),
+        }
+    }
+
     pub fn as_error_mut(&mut self) -> Option<&mut String> {
         if let Value::Error(e) = self {
             return Some(e);